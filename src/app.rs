@@ -2,42 +2,138 @@ use crate::prelude::*;
 
 pub async fn run(cfg: Config) -> Result<()> {
     // init application state data
-    let app_state = actix_web::web::Data::new(State::new(cfg.cred()).await?);
+    let app_state = actix_web::web::Data::new(State::new(&cfg).await?);
+    // routes live under the configured prefix when served from a subpath behind a proxy
+    let base_path = cfg.proxy().base_path();
 
     actix_web::HttpServer::new(move || {
         actix_web::App::new()
             .wrap(actix_web::middleware::Logger::default())
             .app_data(app_state.clone())
             .service(
-                actix_web::web::resource(["/rest/getCoverArt", "/rest/getCoverArt.view"])
-                    .route(actix_web::web::get().to(get_cover_art)),
-            )
-            .service(
-                actix_web::web::resource(["/rest/getLicense", "/rest/getLicense.view"])
-                    .route(actix_web::web::get().to(get_license)),
-            )
-            .service(
-                actix_web::web::resource([
-                    "/rest/getOpenSubsonicExtensions",
-                    "/rest/getOpenSubsonicExtensions.view",
-                ])
-                .route(actix_web::web::get().to(get_open_subsonic_extensions)),
-            )
-            .service(
-                actix_web::web::resource(["/rest/getSong", "/rest/getSong.view"])
-                    .route(actix_web::web::get().to(get_song)),
-            )
-            .service(
-                actix_web::web::resource(["/rest/ping", "/rest/ping.view"])
-                    .route(actix_web::web::get().to(ping)),
-            )
-            .service(
-                actix_web::web::resource(["/rest/search3", "/rest/search3.view"])
-                    .route(actix_web::web::get().to(search3)),
-            )
-            .service(
-                actix_web::web::resource(["/rest/stream", "/rest/stream.view"])
-                    .route(actix_web::web::get().to(stream)),
+                actix_web::web::scope(&base_path)
+                    .service(
+                        actix_web::web::resource([
+                            "/rest/changePassword",
+                            "/rest/changePassword.view",
+                        ])
+                        .route(actix_web::web::get().to(change_password)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/createApiKey", "/rest/createApiKey.view"])
+                            .route(actix_web::web::get().to(create_api_key)),
+                    )
+                    .service(
+                        actix_web::web::resource([
+                            "/rest/createPlaylist",
+                            "/rest/createPlaylist.view",
+                        ])
+                        .route(actix_web::web::get().to(create_playlist)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/createUser", "/rest/createUser.view"])
+                            .route(actix_web::web::get().to(create_user)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/deleteApiKey", "/rest/deleteApiKey.view"])
+                            .route(actix_web::web::get().to(delete_api_key)),
+                    )
+                    .service(
+                        actix_web::web::resource([
+                            "/rest/deletePlaylist",
+                            "/rest/deletePlaylist.view",
+                        ])
+                        .route(actix_web::web::get().to(delete_playlist)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/deleteUser", "/rest/deleteUser.view"])
+                            .route(actix_web::web::get().to(delete_user)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/download", "/rest/download.view"])
+                            .route(actix_web::web::get().to(download)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getAlbum", "/rest/getAlbum.view"])
+                            .route(actix_web::web::get().to(get_album)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getApiKeys", "/rest/getApiKeys.view"])
+                            .route(actix_web::web::get().to(get_api_keys)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getArtist", "/rest/getArtist.view"])
+                            .route(actix_web::web::get().to(get_artist)),
+                    )
+                    .service(
+                        actix_web::web::resource([
+                            "/rest/getArtistInfo2",
+                            "/rest/getArtistInfo2.view",
+                        ])
+                        .route(actix_web::web::get().to(get_artist_info2)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getArtists", "/rest/getArtists.view"])
+                            .route(actix_web::web::get().to(get_artists)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getCoverArt", "/rest/getCoverArt.view"])
+                            .route(actix_web::web::get().to(get_cover_art)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getLicense", "/rest/getLicense.view"])
+                            .route(actix_web::web::get().to(get_license)),
+                    )
+                    .service(
+                        actix_web::web::resource([
+                            "/rest/getOpenSubsonicExtensions",
+                            "/rest/getOpenSubsonicExtensions.view",
+                        ])
+                        .route(actix_web::web::get().to(get_open_subsonic_extensions)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getPlaylist", "/rest/getPlaylist.view"])
+                            .route(actix_web::web::get().to(get_playlist)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getPlaylists", "/rest/getPlaylists.view"])
+                            .route(actix_web::web::get().to(get_playlists)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getSong", "/rest/getSong.view"])
+                            .route(actix_web::web::get().to(get_song)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getUser", "/rest/getUser.view"])
+                            .route(actix_web::web::get().to(get_user)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/getUsers", "/rest/getUsers.view"])
+                            .route(actix_web::web::get().to(get_users)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/ping", "/rest/ping.view"])
+                            .route(actix_web::web::get().to(ping)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/search3", "/rest/search3.view"])
+                            .route(actix_web::web::get().to(search3)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/stream", "/rest/stream.view"])
+                            .route(actix_web::web::get().to(stream)),
+                    )
+                    .service(
+                        actix_web::web::resource([
+                            "/rest/updatePlaylist",
+                            "/rest/updatePlaylist.view",
+                        ])
+                        .route(actix_web::web::get().to(update_playlist)),
+                    )
+                    .service(
+                        actix_web::web::resource(["/rest/updateUser", "/rest/updateUser.view"])
+                            .route(actix_web::web::get().to(update_user)),
+                    ),
             )
     })
     .bind(cfg.addr())?
@@ -1,56 +1,197 @@
+use std::time::Instant;
+
 use crate::prelude::*;
 
-pub async fn authenticate(data: &Data<State>, params: &HashMap<String, String>) -> bool {
-    let acct = data.cred().account();
-
-    // Accept user=admin and password=admin
-    let (u, p) = if let (Some(u), Some(p)) = (params.get("u"), params.get("p")) {
-        let password = if let Some(hex) = p.strip_prefix("enc:") {
-            let bytes: Vec<u8> = (0..hex.len())
-                .step_by(2)
-                .filter_map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
-                .collect();
-            String::from_utf8(bytes).unwrap_or_default()
+/// decodes a subsonic password parameter, which clients may hex-encode behind `enc:`. returns
+/// `None` if the hex is malformed or doesn't decode to utf-8.
+pub fn decode_password(p: &str) -> Option<String> {
+    let Some(hex) = p.strip_prefix("enc:") else {
+        return Some(p.to_string());
+    };
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let hi = char::from(pair[0]).to_digit(16)?;
+            let lo = char::from(pair[1]).to_digit(16)?;
+            Some((hi << 4 | lo) as u8)
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// compares two secrets in time independent of where they differ, so response times don't
+/// reveal how much of a guess was right. only the length may leak.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+/// salts used in successful token logins, so that a captured `(t, s)` pair can't be replayed
+/// within the window. a zero window turns the check off. past `max_entries` the oldest salt is
+/// forgotten early to make room.
+pub struct SaltCache {
+    window: Duration,
+    max_entries: usize,
+    seen: HashMap<(String, String), Instant>,
+    last_prune: Instant,
+}
+
+impl SaltCache {
+    pub fn new(window: Duration, max_entries: usize) -> Self {
+        Self {
+            window,
+            max_entries,
+            seen: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// records `username` logging in with `salt`, returning `false` if they already did within
+    /// the window.
+    pub fn insert(&mut self, username: &str, salt: &str) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.last_prune) >= self.window {
+            self.prune(now);
+        }
+
+        let key = (username.to_string(), salt.to_string());
+        if self
+            .seen
+            .get(&key)
+            .is_some_and(|used| now.duration_since(*used) < self.window)
+        {
+            return false;
+        }
+        if !self.seen.contains_key(&key) && self.seen.len() >= self.max_entries {
+            self.make_room(now);
+        }
+        self.seen.insert(key, now);
+        true
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.seen
+            .retain(|_, used| now.duration_since(*used) < window);
+        self.last_prune = now;
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        self.prune(now);
+        if self.seen.len() < self.max_entries {
+            return;
+        }
+        let oldest = self
+            .seen
+            .iter()
+            .min_by_key(|(_, used)| **used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.seen.remove(&key);
+        }
+    }
+}
+
+/// checks the request's credentials, returning the user they belong to or the subsonic error to
+/// fail with.
+pub async fn authenticate(
+    data: &Data<State>,
+    params: &HashMap<String, String>,
+) -> Result<User, ErrorCode> {
+    let users = data.users().lock().await;
+
+    // api key auth (apiKey), which replaces the username and must not be mixed with other schemes
+    if let Some(key) = params.get("apiKey") {
+        if ["u", "p", "t", "s"].iter().any(|k| params.contains_key(*k)) {
+            return Err(ErrorCode::ConflictingAuth);
+        }
+        return users
+            .by_api_key(key)
+            .cloned()
+            .ok_or(ErrorCode::InvalidApiKey);
+    }
+
+    let user = params
+        .get("u")
+        .and_then(|u| users.get(u))
+        .ok_or(ErrorCode::WrongCredentials)?;
+    // accounts without a password can only use api keys
+    let Some(password) = &user.password else {
+        return Err(ErrorCode::AuthNotSupported);
+    };
+
+    // password auth (u, p), plain or hex-encoded
+    if let Some(p) = params.get("p") {
+        let valid =
+            decode_password(p).is_some_and(|p| constant_time_eq(p.as_bytes(), password.as_bytes()));
+        return if valid {
+            Ok(user.clone())
         } else {
-            p.clone()
+            Err(ErrorCode::WrongCredentials)
         };
-        (u, password)
-    // Token auth (u, t, s)
-    } else if let (Some(u), Some(t), Some(s)) = (params.get("u"), params.get("t"), params.get("s"))
-    {
-        let mut hasher = Md5::new();
-        hasher.update(format!("{}{}", acct.pass(), s));
-        let result = hasher.finalize();
-        let expected = format!("{:x}", result);
-        return u == acct.user() && t == &expected;
-    } else {
-        return false;
+    }
+
+    // token auth (u, t, s)
+    let (Some(t), Some(s)) = (params.get("t"), params.get("s")) else {
+        return Err(ErrorCode::WrongCredentials);
     };
-    u == acct.user() && p == acct.pass()
+    let mut hasher = Md5::new();
+    hasher.update(format!("{password}{s}"));
+    let result = hasher.finalize();
+    let expected = format!("{:x}", result);
+    if !constant_time_eq(t.as_bytes(), expected.as_bytes()) {
+        return Err(ErrorCode::WrongCredentials);
+    }
+    if !data.used_salts().lock().await.insert(&user.username, s) {
+        log::warn!("Rejected reused salt from {}.", user.username);
+        return Err(ErrorCode::WrongCredentials);
+    }
+    Ok(user.clone())
 }
 
+/// rate-limits and authenticates a request, returning the user it was made by. failures count
+/// against both the client's address and the username it tried.
 pub async fn verify(
     req: HttpRequest,
     data: &Data<State>,
     params: &HashMap<String, String>,
-) -> bool {
-    if let Some(addr) = req.peer_addr() {
-        let mut rate_limits = data.rate_limits().lock().await;
-
-        let limit = rate_limits.entry(addr.ip()).or_default();
-
-        if limit.incr() {
-            if authenticate(data, params).await {
-                limit.reset();
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+) -> Result<User, ErrorCode> {
+    // a trusted proxy that already authenticated the user vouches for them
+    if let Some(username) = proxy_user(&req, data.proxy()) {
+        let users = data.users().lock().await;
+        return users.get(username).cloned().ok_or_else(|| {
+            log::error!("Proxy authenticated unknown user {username}.");
+            ErrorCode::WrongCredentials
+        });
+    }
+
+    let ip = client_ip(&req, data.proxy()).ok_or(ErrorCode::WrongCredentials)?;
+    let mut keys = vec![RateLimitKey::Ip(ip)];
+    keys.extend(params.get("u").cloned().map(RateLimitKey::User));
+
+    let mut rate_limiter = data.rate_limiter().lock().await;
+    if rate_limiter.is_locked(&keys) {
+        return Err(ErrorCode::WrongCredentials);
+    }
+    match authenticate(data, params).await {
+        Ok(user) => {
+            rate_limiter.succeed(&user.username);
+            Ok(user)
+        }
+        Err(code) => {
+            rate_limiter.fail(&keys);
+            Err(code)
         }
-    } else {
-        false
     }
 }
 
@@ -89,3 +230,80 @@ pub async fn create_session() -> Result<Session> {
 
     Ok(session)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_password() {
+        assert_eq!(decode_password("sesame").as_deref(), Some("sesame"));
+        assert_eq!(decode_password("").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn hex_password() {
+        assert_eq!(
+            decode_password("enc:736573616d65").as_deref(),
+            Some("sesame")
+        );
+        assert_eq!(
+            decode_password("enc:736573616D65").as_deref(),
+            Some("sesame")
+        );
+        assert_eq!(decode_password("enc:").as_deref(), Some(""));
+        assert_eq!(decode_password("enc:c3a9").as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn malformed_hex_password() {
+        // odd length
+        assert_eq!(decode_password("enc:736"), None);
+        // not hex digits, including signs that integer parsing would accept
+        assert_eq!(decode_password("enc:zz"), None);
+        assert_eq!(decode_password("enc:+1"), None);
+        assert_eq!(decode_password("enc:é1"), None);
+        // valid hex that isn't utf-8
+        assert_eq!(decode_password("enc:ff"), None);
+        assert_eq!(decode_password("enc:c3"), None);
+    }
+
+    #[test]
+    fn secrets_compare() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn salt_reuse() {
+        let mut salts = SaltCache::new(Duration::from_secs(60), 10);
+        assert!(salts.insert("alice", "a1"));
+        assert!(!salts.insert("alice", "a1"));
+        assert!(salts.insert("bob", "a1"));
+
+        // a zero window accepts every salt
+        let mut salts = SaltCache::new(Duration::ZERO, 10);
+        assert!(salts.insert("alice", "a1"));
+        assert!(salts.insert("alice", "a1"));
+    }
+
+    #[test]
+    fn salt_cap() {
+        let mut salts = SaltCache::new(Duration::from_secs(60), 2);
+        assert!(salts.insert("alice", "a1"));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(salts.insert("alice", "a2"));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(salts.insert("alice", "a3"));
+        assert_eq!(salts.seen.len(), 2);
+
+        // the oldest salt made room, the newer ones are still rejected
+        assert!(!salts.insert("alice", "a3"));
+        assert!(!salts.insert("alice", "a2"));
+        assert!(salts.insert("alice", "a1"));
+    }
+}
@@ -26,15 +26,282 @@ fn local_addr() -> SocketAddr {
 #[derive(Debug, serde::Deserialize)]
 struct CredentialsConfig {
     user: String,
-    pass: String,
+    #[serde(default)]
+    pass: Option<String>,
     client_id: String,
     client_secret: String,
+    #[serde(default)]
+    spotify_playlists: Vec<String>,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    transcode: TranscodeConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    proxy: ProxyConfig,
+    #[serde(default)]
+    auth: AuthConfig,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    path: PathBuf,
+    song_ttl_days: u64,
+    song_max_mb: u64,
+    artist_ttl_days: u64,
+    artist_max_mb: u64,
+    playlist_ttl_mins: u64,
+    playlist_max_mb: u64,
+    cover_ttl_days: u64,
+    cover_max_mb: u64,
+    cover_memory_mb: u64,
+    transcode_dir: PathBuf,
+    transcode_max_mb: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("cache.redb"),
+            song_ttl_days: 30,
+            song_max_mb: 64,
+            artist_ttl_days: 90,
+            artist_max_mb: 16,
+            playlist_ttl_mins: 60,
+            playlist_max_mb: 4,
+            cover_ttl_days: 30,
+            cover_max_mb: 512,
+            cover_memory_mb: 64,
+            transcode_dir: PathBuf::from("transcodes"),
+            transcode_max_mb: 2048,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    pub const fn song_ttl(&self) -> Duration {
+        Duration::from_secs(self.song_ttl_days * 24 * 60 * 60)
+    }
+
+    pub const fn song_max_bytes(&self) -> u64 {
+        self.song_max_mb * 1024 * 1024
+    }
+
+    pub const fn artist_ttl(&self) -> Duration {
+        Duration::from_secs(self.artist_ttl_days * 24 * 60 * 60)
+    }
+
+    pub const fn artist_max_bytes(&self) -> u64 {
+        self.artist_max_mb * 1024 * 1024
+    }
+
+    pub const fn playlist_ttl(&self) -> Duration {
+        Duration::from_secs(self.playlist_ttl_mins * 60)
+    }
+
+    pub const fn playlist_max_bytes(&self) -> u64 {
+        self.playlist_max_mb * 1024 * 1024
+    }
+
+    pub const fn cover_ttl(&self) -> Duration {
+        Duration::from_secs(self.cover_ttl_days * 24 * 60 * 60)
+    }
+
+    pub const fn cover_max_bytes(&self) -> u64 {
+        self.cover_max_mb * 1024 * 1024
+    }
+
+    pub const fn cover_memory_bytes(&self) -> usize {
+        self.cover_memory_mb as usize * 1024 * 1024
+    }
+
+    pub fn transcode_dir(&self) -> &std::path::Path {
+        &self.transcode_dir
+    }
+
+    pub const fn transcode_max_bytes(&self) -> u64 {
+        self.transcode_max_mb * 1024 * 1024
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct TranscodeConfig {
+    format: OutputFormat,
+    bit_rate: u32,
+    complexity: u8,
+    vbr: VbrMode,
+    normalisation: NormalisationMode,
+    // defaults keyed by subsonic username and by client name (the `c` parameter)
+    user_bit_rates: HashMap<String, u32>,
+    client_bit_rates: HashMap<String, u32>,
+    client_formats: HashMap<String, OutputFormat>,
+}
+
+impl Default for TranscodeConfig {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            bit_rate: DEFAULT_BIT_RATE,
+            complexity: 10,
+            vbr: VbrMode::default(),
+            normalisation: NormalisationMode::default(),
+            user_bit_rates: HashMap::new(),
+            client_bit_rates: HashMap::new(),
+            client_formats: HashMap::new(),
+        }
+    }
+}
+
+impl TranscodeConfig {
+    /// resolves the encoder settings for a stream. an explicit `format` beats the client's
+    /// default format, which beats the global one. for the bitrate, a client default beats a user
+    /// default, which beats the global one, and `max_bit_rate` (kbps, 0 = unlimited) caps
+    /// whichever applies.
+    pub fn encoder_settings(
+        &self,
+        user: Option<&str>,
+        client: Option<&str>,
+        format: Option<OutputFormat>,
+        max_bit_rate: Option<u32>,
+    ) -> EncoderSettings {
+        let format = format
+            .or_else(|| client.and_then(|c| self.client_formats.get(c)).copied())
+            .unwrap_or(self.format);
+
+        let default = client
+            .and_then(|c| self.client_bit_rates.get(c))
+            .or_else(|| user.and_then(|u| self.user_bit_rates.get(u)))
+            .copied()
+            .unwrap_or(self.bit_rate);
+        let bit_rate = match max_bit_rate {
+            Some(max) if max > 0 => default.min(max),
+            _ => default,
+        };
+
+        EncoderSettings {
+            format,
+            bit_rate: format.clamp_bit_rate(bit_rate),
+            complexity: self.complexity.min(OPUS_MAX_COMPLEXITY),
+            vbr: self.vbr,
+            normalisation: self.normalisation,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    ip_max_failures: u32,
+    user_max_failures: u32,
+    window_secs: u64,
+    lockout_secs: u64,
+    max_entries: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            ip_max_failures: 10,
+            user_max_failures: 20,
+            window_secs: 10 * 60,
+            lockout_secs: 15 * 60,
+            max_entries: 10_000,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub const fn ip_max_failures(&self) -> u32 {
+        self.ip_max_failures
+    }
+
+    pub const fn user_max_failures(&self) -> u32 {
+        self.user_max_failures
+    }
+
+    pub const fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+
+    pub const fn lockout(&self) -> Duration {
+        Duration::from_secs(self.lockout_secs)
+    }
+
+    pub const fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    // 0 accepts reused salts, which some clients rely on for stream urls
+    salt_window_secs: u64,
+    max_salts: usize,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            salt_window_secs: 0,
+            max_salts: 100_000,
+        }
+    }
+}
+
+impl AuthConfig {
+    /// how long a token salt stays used after a successful login.
+    pub const fn salt_window(&self) -> Duration {
+        Duration::from_secs(self.salt_window_secs)
+    }
+
+    pub const fn max_salts(&self) -> usize {
+        self.max_salts
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    trusted_proxies: Vec<IpRange>,
+    user_header: Option<String>,
+    base_path: String,
+}
+
+impl ProxyConfig {
+    /// whether requests from `ip` may speak for the client through forwarding headers.
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+
+    /// the header a trusted proxy passes the authenticated username in, if any.
+    pub fn user_header(&self) -> Option<&str> {
+        self.user_header.as_deref()
+    }
+
+    /// the prefix the `/rest` routes are served under: empty, or starting with a slash and
+    /// without a trailing one.
+    pub fn base_path(&self) -> String {
+        let path = self.base_path.trim_matches('/');
+        if path.is_empty() {
+            String::new()
+        } else {
+            format!("/{path}")
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Account {
     user: String,
-    pass: String,
+    pass: Option<String>,
 }
 
 impl Account {
@@ -42,8 +309,9 @@ impl Account {
         self.user.as_str()
     }
 
-    pub const fn pass(&self) -> &str {
-        self.pass.as_str()
+    /// `None` if the account only authenticates with api keys.
+    pub fn pass(&self) -> Option<&str> {
+        self.pass.as_deref()
     }
 }
 
@@ -92,6 +360,12 @@ pub struct ArgsConfig {
 pub struct Config {
     addr: SocketAddr,
     cred: Credentials,
+    spotify_playlists: Vec<String>,
+    cache: CacheConfig,
+    transcode: TranscodeConfig,
+    rate_limit: RateLimitConfig,
+    proxy: ProxyConfig,
+    auth: AuthConfig,
 }
 
 impl Config {
@@ -123,6 +397,12 @@ impl Config {
             pass,
             client_id,
             client_secret,
+            spotify_playlists,
+            cache,
+            transcode,
+            rate_limit,
+            proxy,
+            auth,
         } = serde_json::from_reader::<_, CredentialsConfig>(rdr)?;
 
         let addr = args.addr;
@@ -133,7 +413,16 @@ impl Config {
                 client_secret,
             },
         };
-        Ok(Self { addr, cred })
+        Ok(Self {
+            addr,
+            cred,
+            spotify_playlists,
+            cache,
+            transcode,
+            rate_limit,
+            proxy,
+            auth,
+        })
     }
 
     pub const fn addr(&self) -> SocketAddr {
@@ -143,4 +432,28 @@ impl Config {
     pub fn cred(&self) -> Credentials {
         self.cred.clone()
     }
+
+    pub fn spotify_playlists(&self) -> &[String] {
+        &self.spotify_playlists
+    }
+
+    pub const fn cache(&self) -> &CacheConfig {
+        &self.cache
+    }
+
+    pub const fn transcode(&self) -> &TranscodeConfig {
+        &self.transcode
+    }
+
+    pub const fn rate_limit(&self) -> &RateLimitConfig {
+        &self.rate_limit
+    }
+
+    pub const fn proxy(&self) -> &ProxyConfig {
+        &self.proxy
+    }
+
+    pub const fn auth(&self) -> &AuthConfig {
+        &self.auth
+    }
 }
@@ -2,10 +2,31 @@ use crate::prelude::*;
 
 // OpenSubsonic
 pub const API_VERSION: &str = "1.16.1";
+pub const IGNORED_ARTICLES: &str = "The El La Los Las Le Les";
+
+// getCoverArt ids for artist images (album ids are passed through unprefixed)
+pub const COVER_ARTIST_PREFIX: &str = "ar-";
 
 // Spotify OAuth helper
 pub const SPOTIFY_CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
 pub const SPOTIFY_REDIRECT_URI: &str = "http://127.0.0.1:8898/login";
 
+// Server-side playlists (ids are prefixed to tell them apart from spotify playlist ids)
+pub const PLAYLISTS_PATH: &str = "playlists.json";
+pub const PLAYLIST_ID_PREFIX: &str = "pl-";
+
+// User accounts besides the one in the config file
+pub const USERS_PATH: &str = "users.json";
+
+// Transcoding (bitrate in kbps for any format, used when neither the config nor the client picks one)
+pub const DEFAULT_BIT_RATE: u32 = 160;
+// cover art size embedded in tagged output — spotify's own 300px variant, so no resizing
+pub const EMBEDDED_COVER_SIZE: u32 = 300;
+// bytes read from a cached transcode per chunk handed to the client
+pub const CACHED_READ_SIZE: usize = 64 * 1024;
+
 // Delays
 pub const DELAY_SEARCH3: Duration = Duration::from_secs(1);
+
+// Timeouts
+pub const STREAM_LOAD_TIMEOUT: Duration = Duration::from_secs(30);
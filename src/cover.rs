@@ -0,0 +1,109 @@
+use std::io::Cursor;
+
+use image::{ImageFormat, ImageReader, imageops::FilterType};
+use rspotify::model::Image;
+
+use crate::prelude::*;
+
+// flac picture type for the front cover
+const PICTURE_TYPE_FRONT_COVER: u32 = 3;
+
+// a variant up to this much larger than requested is served as-is rather than resized
+const COVER_SIZE_SLACK: f32 = 1.25;
+
+// sizes covers are served at. requests round up to the next one, so a handful of resized copies
+// per cover are cached rather than one per distinct `size`. spotify's largest variant is 640px
+const COVER_SIZE_BUCKETS: [u32; 6] = [64, 128, 256, 300, 512, 640];
+
+/// rounds a requested cover size up to a bucket. sizes of 0 or beyond the largest bucket get the
+/// full-size cover.
+pub fn cover_size_bucket(size: Option<u32>) -> Option<u32> {
+    let size = size.filter(|&s| s > 0)?;
+    COVER_SIZE_BUCKETS.into_iter().find(|&b| b >= size)
+}
+
+/// what to serve for a requested cover size.
+pub enum CoverSource<'a> {
+    /// a spotify variant close enough to the requested size
+    Variant(&'a Image),
+    /// the smallest variant that is still larger than requested, to be downscaled to `size`
+    Resize(&'a Image, u32),
+}
+
+impl<'a> CoverSource<'a> {
+    /// chooses among spotify's image variants (typically 640, 300 and 64px squares).
+    pub fn pick(images: &'a [Image], size: Option<u32>) -> Option<Self> {
+        // spotify returns images sorted largest first
+        let largest = images.first()?;
+        let Some(size) = size else {
+            return Some(Self::Variant(largest));
+        };
+
+        // variants without dimensions can't be compared, so treat them as the largest
+        let width = |img: &Image| img.width.unwrap_or(u32::MAX);
+        let Some(fit) = images
+            .iter()
+            .filter(|img| width(img) >= size)
+            .min_by_key(|img| width(img))
+        else {
+            // no upscaling, the largest variant is as good as it gets
+            return Some(Self::Variant(largest));
+        };
+
+        if width(fit) as f32 <= size as f32 * COVER_SIZE_SLACK {
+            Some(Self::Variant(fit))
+        } else {
+            Some(Self::Resize(fit, size))
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            Self::Variant(img) | Self::Resize(img, _) => &img.url,
+        }
+    }
+}
+
+/// downscales an image so that its longest side is `size` pixels, re-encoding as jpeg.
+pub fn resize_cover(bytes: &[u8], size: u32) -> Result<Bytes> {
+    let img = image::load_from_memory(bytes)?;
+    let resized = img.resize(size, size, FilterType::Lanczos3);
+
+    let mut out = Cursor::new(Vec::new());
+    resized.to_rgb8().write_to(&mut out, ImageFormat::Jpeg)?;
+    Ok(Bytes::from(out.into_inner()))
+}
+
+/// sniffs the mime type from the image's magic bytes, defaulting to jpeg like the spotify cdn.
+pub fn cover_content_type(bytes: &[u8]) -> &'static str {
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Png) => "image/png",
+        Ok(ImageFormat::WebP) => "image/webp",
+        Ok(ImageFormat::Gif) => "image/gif",
+        _ => "image/jpeg",
+    }
+}
+
+/// a flac METADATA_BLOCK_PICTURE body holding `bytes` as the front cover, as embedded in flac
+/// files and (base64 encoded) in vorbis comments.
+pub fn picture_block(bytes: &[u8]) -> Vec<u8> {
+    let (width, height) = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .unwrap_or((0, 0));
+    let mime = cover_content_type(bytes).as_bytes();
+
+    let mut block = Vec::with_capacity(bytes.len() + 64);
+    block.extend_from_slice(&PICTURE_TYPE_FRONT_COVER.to_be_bytes());
+    block.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+    block.extend_from_slice(mime);
+    block.extend_from_slice(&0u32.to_be_bytes()); // no description
+    block.extend_from_slice(&width.to_be_bytes());
+    block.extend_from_slice(&height.to_be_bytes());
+    block.extend_from_slice(&24u32.to_be_bytes()); // colour depth
+    block.extend_from_slice(&0u32.to_be_bytes()); // not indexed
+    block.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    block.extend_from_slice(bytes);
+    block
+}
@@ -0,0 +1,153 @@
+use flacenc::bitsink::MemSink;
+use flacenc::component::{BitRepr, MetadataBlockData, Stream};
+use flacenc::config;
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Fill, FrameBuf};
+
+use crate::prelude::*;
+
+// samples per channel in each flac frame, libFLAC's default
+const FLAC_BLOCK_SIZE: usize = 4096;
+const FLAC_BLOCK_SAMPLES: usize = FLAC_BLOCK_SIZE * 2;
+// metadata block types, see https://xiph.org/flac/format.html#metadata_block_header
+const FLAC_BLOCK_VORBIS_COMMENT: u8 = 4;
+const FLAC_BLOCK_PICTURE: u8 = 6;
+// metadata block lengths are 24 bits
+const FLAC_MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+// where the stream info block's body sits in the header, after `fLaC` and its block header
+const FLAC_STREAM_INFO_OFFSET: usize = 8;
+
+/// encodes a 16-bit stereo flac stream frame by frame. the stream info header leaves the total
+/// sample count and md5 unset, which the format allows for streams of unknown length, until
+/// [`AudioEncoder::finish_header`] fills them in.
+pub struct FlacStreamer {
+    config: Verified<config::Encoder>,
+    // holds only the stream info, the frames are written out as soon as they are encoded
+    stream: Stream,
+    framebuf: FrameBuf,
+    // internal sample buffer to accumulate until a full block is available
+    buffer: Vec<i32>,
+    frame_number: usize,
+    // samples per channel and md5 of everything encoded so far
+    total_samples: usize,
+    md5: Md5,
+}
+
+impl FlacStreamer {
+    pub fn new() -> Self {
+        let config = config::Encoder::default()
+            .into_verified()
+            .expect("failed to verify flac config");
+
+        let mut stream =
+            Stream::new(SOURCE_SAMPLE_RATE as usize, 2, 16).expect("failed to create flac stream");
+        stream
+            .stream_info_mut()
+            .set_block_sizes(FLAC_BLOCK_SIZE, FLAC_BLOCK_SIZE)
+            .expect("failed to set flac block size");
+
+        Self {
+            config,
+            stream,
+            framebuf: FrameBuf::with_size(2, FLAC_BLOCK_SIZE)
+                .expect("failed to create flac buffer"),
+            buffer: Vec::with_capacity(FLAC_BLOCK_SAMPLES),
+            frame_number: 0,
+            total_samples: 0,
+            md5: Md5::new(),
+        }
+    }
+
+    /// encodes the interleaved samples of one block (the last one may be shorter).
+    fn encode_block(&mut self, samples: &[i32]) -> Vec<u8> {
+        self.framebuf.resize(samples.len() / 2);
+        self.framebuf
+            .fill_interleaved(samples)
+            .expect("failed to fill flac buffer");
+
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            self.frame_number,
+            self.stream.stream_info(),
+        )
+        .expect("flac encode failed");
+        self.frame_number += 1;
+
+        let mut sink = MemSink::<u8>::with_capacity(frame.count_bits());
+        frame.write(&mut sink).expect("failed to write flac frame");
+        sink.into_inner()
+    }
+}
+
+impl AudioEncoder for FlacStreamer {
+    fn sample_rate(&self) -> u32 {
+        SOURCE_SAMPLE_RATE
+    }
+
+    /// the `fLaC` marker followed by the stream info, vorbis comment and picture blocks.
+    fn header_bytes(&mut self, tags: &TrackTags) -> Vec<u8> {
+        let comments = vorbis_comment_block(&tags.vorbis_comments());
+        let blocks = std::iter::once((FLAC_BLOCK_VORBIS_COMMENT, comments)).chain(
+            tags.cover
+                .as_deref()
+                .map(|cover| (FLAC_BLOCK_PICTURE, picture_block(cover))),
+        );
+        for (tag, data) in blocks {
+            if data.len() > FLAC_MAX_BLOCK_LEN {
+                log::warn!("flac: metadata block {tag} too large, leaving it out");
+                continue;
+            }
+            let block = MetadataBlockData::new_unknown(tag, &data)
+                .expect("failed to create flac metadata block");
+            self.stream.add_metadata_block(block);
+        }
+
+        let mut sink = MemSink::<u8>::new();
+        self.stream
+            .write(&mut sink)
+            .expect("failed to write flac header");
+        sink.into_inner()
+    }
+
+    fn encode_chunk(&mut self, pcm: &[i16]) -> Vec<u8> {
+        self.buffer.extend(pcm.iter().map(|&s| s as i32));
+        // the md5 covers the samples as little-endian bytes, interleaved
+        let bytes: Vec<u8> = pcm.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.md5.update(&bytes);
+        self.total_samples += pcm.len() / 2;
+
+        let mut out = Vec::new();
+        while self.buffer.len() >= FLAC_BLOCK_SAMPLES {
+            let block: Vec<i32> = self.buffer.drain(..FLAC_BLOCK_SAMPLES).collect();
+            out.extend(self.encode_block(&block));
+        }
+        out
+    }
+
+    fn flush(&mut self) -> Vec<u8> {
+        if self.buffer.is_empty() {
+            return Vec::new();
+        }
+        // unlike opus, flac's final frame may simply be shorter than the others
+        let block = std::mem::take(&mut self.buffer);
+        self.encode_block(&block)
+    }
+
+    /// writes the total sample count and md5 into the stream info block.
+    fn finish_header(&self, header: &mut [u8], _len: u64) {
+        let mut info = self.stream.stream_info().clone();
+        info.set_total_samples(self.total_samples);
+        info.set_md5_digest(&self.md5.clone().finalize().into());
+
+        let mut sink = MemSink::<u8>::new();
+        info.write(&mut sink)
+            .expect("failed to write flac stream info");
+        let info = sink.into_inner();
+        if let Some(dest) =
+            header.get_mut(FLAC_STREAM_INFO_OFFSET..FLAC_STREAM_INFO_OFFSET + info.len())
+        {
+            dest.copy_from_slice(&info);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use crate::prelude::*;
 
 #[derive(Serialize)]
@@ -13,6 +15,40 @@ pub enum Status {
     Failed,
 }
 
+/// subsonic error codes. failures are still sent with http 200, and clients read the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    Generic = 0,
+    MissingParameter = 10,
+    WrongCredentials = 40,
+    AuthNotSupported = 42,
+    ConflictingAuth = 43,
+    InvalidApiKey = 44,
+    NotAuthorized = 50,
+    NotFound = 70,
+}
+
+impl ErrorCode {
+    pub const fn message(self) -> &'static str {
+        match self {
+            Self::Generic => "A generic error.",
+            Self::MissingParameter => "Required parameter is missing.",
+            Self::WrongCredentials => "Wrong username or password.",
+            Self::AuthNotSupported => "Provided authentication mechanism not supported.",
+            Self::ConflictingAuth => "Multiple conflicting authentication mechanisms provided.",
+            Self::InvalidApiKey => "Invalid API key.",
+            Self::NotAuthorized => "User is not authorized for the given operation.",
+            Self::NotFound => "The requested data was not found.",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResponseError {
+    code: u32,
+    message: Cow<'static, str>,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ResponseBody<T> {
@@ -21,6 +57,8 @@ pub struct ResponseBody<T> {
     r#type: &'static str,
     server_version: &'static str,
     open_subsonic: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
     #[serde(skip_serializing_if = "Option::is_none", flatten)]
     data: Option<T>,
 }
@@ -33,6 +71,7 @@ impl<T: Serialize> ResponseBody<T> {
             r#type: env!("CARGO_PKG_NAME"),
             server_version: env!("CARGO_PKG_VERSION"),
             open_subsonic: true,
+            error: None,
             data,
         }
     }
@@ -45,20 +84,167 @@ impl<T: Serialize> ResponseBody<T> {
         Self::new(Status::Ok, Some(data))
     }
 
-    pub const fn failed() -> Self {
-        Self::new(Status::Failed, None)
+    /// a failed response carrying the code's standard message.
+    pub fn error(code: ErrorCode) -> Self {
+        Self::error_with(code, code.message())
     }
 
-    pub fn into_response(self) -> HttpResponse {
-        let mut res = match self.status {
-            Status::Ok => HttpResponse::Ok(),
-            Status::Failed => HttpResponse::InternalServerError(),
-        };
-        res.json(SubsonicResponse { response: self })
+    pub fn error_with(code: ErrorCode, message: impl Into<Cow<'static, str>>) -> Self {
+        let mut body = Self::new(Status::Failed, None);
+        body.error = Some(ResponseError {
+            code: code as u32,
+            message: message.into(),
+        });
+        body
+    }
+
+    /// renders the response in the format requested by the `f` parameter.
+    pub fn into_response(self, query: &HashMap<String, String>) -> HttpResponse {
+        let res = SubsonicResponse { response: self };
+        match Format::from_query(query) {
+            Format::Json => HttpResponse::Ok().json(res),
+            Format::Jsonp(callback) => match serde_json::to_string(&res) {
+                Ok(json) => HttpResponse::Ok()
+                    .content_type("application/javascript; charset=utf-8")
+                    .body(format!("{callback}({json});")),
+                Err(_) => HttpResponse::InternalServerError().finish(),
+            },
+            Format::Xml => match serde_json::to_value(&res) {
+                Ok(value) => HttpResponse::Ok()
+                    .content_type("text/xml; charset=utf-8")
+                    .body(to_xml(&value)),
+                Err(_) => HttpResponse::InternalServerError().finish(),
+            },
+        }
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// response encodings selectable through the `f` parameter. subsonic defaults to xml.
+pub enum Format {
+    Xml,
+    Json,
+    Jsonp(String),
+}
+
+impl Format {
+    pub fn from_query(query: &HashMap<String, String>) -> Self {
+        match query.get("f").map(String::as_str) {
+            Some("json") => Self::Json,
+            Some("jsonp") => match query.get("callback") {
+                // the callback ends up in executable script, so only allow identifier characters
+                Some(cb)
+                    if !cb.is_empty()
+                        && cb
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "_$.".contains(c)) =>
+                {
+                    Self::Jsonp(cb.clone())
+                }
+                _ => Self::Json,
+            },
+            _ => Self::Xml,
+        }
+    }
+}
+
+/// maps the json form of a response onto subsonic's xml schema: scalar fields become
+/// attributes, objects become child elements, arrays become repeated elements, and a
+/// `value` field becomes text content.
+fn to_xml(value: &serde_json::Value) -> String {
+    let mut out = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    if let Some(serde_json::Value::Object(root)) = value.get("subsonic-response") {
+        write_xml_element(
+            &mut out,
+            "subsonic-response",
+            root,
+            Some("http://subsonic.org/restapi"),
+        );
+    }
+    out
+}
+
+fn write_xml_element(
+    out: &mut String,
+    name: &str,
+    fields: &serde_json::Map<String, serde_json::Value>,
+    xmlns: Option<&str>,
+) {
+    use serde_json::Value;
+
+    out.push('<');
+    out.push_str(name);
+    if let Some(xmlns) = xmlns {
+        out.push_str(&format!(r#" xmlns="{xmlns}""#));
+    }
+    for (key, value) in fields {
+        if key == "value" {
+            continue;
+        }
+        if let Some(text) = xml_scalar(value) {
+            out.push_str(&format!(r#" {key}="{}""#, xml_escape(&text)));
+        }
+    }
+
+    let text = fields.get("value").and_then(xml_scalar);
+    let has_children = fields
+        .values()
+        .any(|v| matches!(v, Value::Object(_) | Value::Array(_)));
+    if text.is_none() && !has_children {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+
+    if let Some(text) = text {
+        out.push_str(&xml_escape(&text));
+    }
+    for (key, value) in fields {
+        match value {
+            Value::Object(child) => write_xml_element(out, key, child, None),
+            Value::Array(items) => {
+                for item in items {
+                    match item {
+                        Value::Object(child) => write_xml_element(out, key, child, None),
+                        other => {
+                            if let Some(text) = xml_scalar(other) {
+                                out.push_str(&format!("<{key}>{}</{key}>", xml_escape(&text)));
+                            }
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    out.push_str(&format!("</{name}>"));
+}
+
+fn xml_scalar(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Clone, Debug, Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Song {
     pub id: String,
@@ -67,25 +253,46 @@ pub struct Song {
     pub track: u32,
     pub duration: u64,
     pub is_dir: bool,
-    pub r#type: &'static str,
-    pub media_type: &'static str,
-    pub suffix: &'static str,
-    pub content_type: &'static str,
+    pub r#type: Cow<'static, str>,
+    pub media_type: Cow<'static, str>,
+    pub suffix: Cow<'static, str>,
+    pub content_type: Cow<'static, str>,
     pub bit_rate: u32,
     pub bit_depth: u32,
     pub sampling_rate: u32,
     pub channel_count: u32,
-    pub transcoded_suffix: &'static str,
-    pub transcoded_content_type: &'static str,
+    pub transcoded_suffix: Cow<'static, str>,
+    pub transcoded_content_type: Cow<'static, str>,
     pub disc_number: i32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub artist: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub album_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub cover_art: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub created: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub explicit_status: Option<&'static str>,
+    pub explicit_status: Option<Cow<'static, str>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub isrc: Vec<String>,
+    /// only known once the track's audio file has been opened
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_gain: Option<ReplayGain>,
+}
+
+/// spotify's loudness normalisation data, gains in db and peaks as linear amplitude.
+#[derive(Clone, Copy, Debug, Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayGain {
+    pub track_gain: f32,
+    pub track_peak: f32,
+    pub album_gain: f32,
+    pub album_peak: f32,
 }
 
 impl Song {
@@ -94,27 +301,386 @@ impl Song {
         let dur = t.duration.to_std().ok()?.as_secs();
 
         Some(Self {
-            id,
-            title: t.name.clone(),
-            album: t.album.name.clone(),
             track: t.track_number,
-            duration: dur,
+            disc_number: t.disc_number,
+            explicit_status: if t.explicit {
+                Some("explicit".into())
+            } else {
+                None
+            },
+            isrc: t.external_ids.get("isrc").cloned().into_iter().collect(),
+            ..Self::new(id, t.name.clone(), dur, &t.album, &t.artists)
+        })
+    }
+
+    /// album track listings omit the album itself, so it is taken from the parent album.
+    pub fn from_album_track(t: &SimplifiedTrack, album: &FullAlbum) -> Option<Self> {
+        let id = t.id.as_ref()?.id().to_string();
+        let dur = t.duration.to_std().ok()?.as_secs();
+
+        let parent = SimplifiedAlbum {
+            id: Some(album.id.clone()),
+            name: album.name.clone(),
+            artists: album.artists.clone(),
+            release_date: Some(album.release_date.clone()),
+            ..Default::default()
+        };
+
+        Some(Self {
+            track: t.track_number,
+            disc_number: t.disc_number,
+            explicit_status: if t.explicit {
+                Some("explicit".into())
+            } else {
+                None
+            },
+            ..Self::new(id, t.name.clone(), dur, &parent, &t.artists)
+        })
+    }
+
+    fn new(
+        id: String,
+        title: String,
+        duration: u64,
+        album: &SimplifiedAlbum,
+        artists: &[SimplifiedArtist],
+    ) -> Self {
+        let album_id = album.id.as_ref().map(|id| id.id().to_string());
+        let artist = artists.first();
+
+        Self {
+            id,
+            title,
+            album: album.name.clone(),
+            track: 0,
+            duration,
             is_dir: false,
-            r#type: "music",
-            media_type: "song",
-            suffix: "ogg",
-            content_type: "audio/ogg",
-            bit_rate: 320,
+            r#type: "music".into(),
+            media_type: "song".into(),
+            suffix: "ogg".into(),
+            content_type: "audio/ogg".into(),
+            bit_rate: DEFAULT_BIT_RATE,
             bit_depth: 16,
             sampling_rate: 44100,
             channel_count: 2,
-            transcoded_suffix: "opus",
-            transcoded_content_type: "audio/ogg; codecs=opus",
-            disc_number: t.disc_number,
-            artist: t.artists.first().map(|a| a.name.clone()),
-            cover_art: t.album.id.as_ref().map(|id| id.id().to_string()),
-            created: t.album.release_date.clone(),
-            explicit_status: if t.explicit { Some("explicit") } else { None },
+            transcoded_suffix: "opus".into(),
+            transcoded_content_type: "audio/ogg; codecs=opus".into(),
+            disc_number: 0,
+            artist: artist.map(|a| a.name.clone()),
+            album_id: album_id.clone(),
+            artist_id: artist
+                .and_then(|a| a.id.as_ref())
+                .map(|id| id.id().to_string()),
+            cover_art: album_id,
+            year: album.release_date.as_deref().and_then(release_year),
+            created: album.release_date.clone(),
+            explicit_status: None,
+            isrc: Vec::new(),
+            replay_gain: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist_image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album_count: Option<u32>,
+}
+
+impl Artist {
+    pub fn from_spotify(a: &FullArtist) -> Self {
+        let id = a.id.id().to_string();
+
+        Self {
+            // artist images are served through getCoverArt under a prefixed id
+            cover_art: (!a.images.is_empty()).then(|| format!("{COVER_ARTIST_PREFIX}{id}")),
+            artist_image_url: a.images.first().map(|img| img.url.clone()),
+            id,
+            name: a.name.clone(),
+            album_count: None,
+        }
+    }
+
+    pub fn from_simplified(a: &SimplifiedArtist) -> Option<Self> {
+        Some(Self {
+            id: a.id.as_ref()?.id().to_string(),
+            name: a.name.clone(),
+            cover_art: None,
+            artist_image_url: None,
+            album_count: None,
         })
     }
 }
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtistWithAlbums {
+    #[serde(flatten)]
+    pub artist: Artist,
+    pub album: Vec<Album>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ArtistIndex {
+    pub name: String,
+    pub artist: Vec<Artist>,
+}
+
+impl ArtistIndex {
+    /// groups artists under the first letter of their name, ignoring leading articles.
+    pub fn group(artists: impl IntoIterator<Item = Artist>) -> Vec<Self> {
+        let mut groups: std::collections::BTreeMap<String, Vec<(String, Artist)>> =
+            Default::default();
+
+        for artist in artists {
+            let key = Self::sort_name(&artist.name).to_uppercase();
+            let name = match key.chars().next() {
+                Some(c) if c.is_alphabetic() => c.to_string(),
+                _ => "#".to_string(),
+            };
+            groups.entry(name).or_default().push((key, artist));
+        }
+
+        groups
+            .into_iter()
+            .map(|(name, mut artists)| {
+                artists.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Self {
+                    name,
+                    artist: artists.into_iter().map(|(_, a)| a).collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn sort_name(name: &str) -> &str {
+        IGNORED_ARTICLES
+            .split(' ')
+            .find_map(|article| {
+                name.strip_prefix(article)
+                    .and_then(|rest| rest.strip_prefix(' '))
+            })
+            .unwrap_or(name)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    pub song_count: u32,
+    pub duration: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub year: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+}
+
+impl Album {
+    pub fn from_spotify(a: &FullAlbum, songs: &[Song]) -> Self {
+        let id = a.id.id().to_string();
+        let artist = a.artists.first();
+
+        Self {
+            cover_art: Some(id.clone()),
+            id,
+            name: a.name.clone(),
+            song_count: songs.len() as u32,
+            duration: songs.iter().map(|s| s.duration).sum(),
+            artist: artist.map(|a| a.name.clone()),
+            artist_id: artist
+                .and_then(|a| a.id.as_ref())
+                .map(|id| id.id().to_string()),
+            year: release_year(&a.release_date),
+            created: Some(a.release_date.clone()),
+        }
+    }
+
+    pub fn from_simplified(a: &SimplifiedAlbum) -> Option<Self> {
+        let id = a.id.as_ref()?.id().to_string();
+        let artist = a.artists.first();
+
+        Some(Self {
+            cover_art: Some(id.clone()),
+            id,
+            name: a.name.clone(),
+            // simplified albums carry no track listing
+            song_count: 0,
+            duration: 0,
+            artist: artist.map(|a| a.name.clone()),
+            artist_id: artist
+                .and_then(|a| a.id.as_ref())
+                .map(|id| id.id().to_string()),
+            year: a.release_date.as_deref().and_then(release_year),
+            created: a.release_date.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumWithSongs {
+    #[serde(flatten)]
+    pub album: Album,
+    pub song: Vec<Song>,
+}
+
+#[derive(Clone, Debug, Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    pub owner: String,
+    pub public: bool,
+    pub song_count: u32,
+    pub duration: u64,
+    pub created: String,
+    pub changed: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<String>,
+    pub readonly: bool,
+}
+
+impl Playlist {
+    pub fn from_stored(p: &StoredPlaylist, songs: &[Song]) -> Self {
+        Self {
+            id: p.id.clone(),
+            name: p.name.clone(),
+            comment: p.comment.clone(),
+            owner: p.owner.clone(),
+            public: p.public,
+            song_count: p.entries.len() as u32,
+            duration: songs.iter().map(|s| s.duration).sum(),
+            created: p.created.to_rfc3339(),
+            changed: p.changed.to_rfc3339(),
+            cover_art: songs.first().and_then(|s| s.cover_art.clone()),
+            readonly: false,
+        }
+    }
+
+    /// spotify playlists are exposed read-only; `songs` may only cover the first page.
+    pub fn from_spotify(p: &FullPlaylist, songs: &[Song]) -> Self {
+        let added = p.tracks.items.iter().filter_map(|item| item.added_at);
+        let now = chrono::Utc::now();
+
+        Self {
+            id: p.id.id().to_string(),
+            name: p.name.clone(),
+            comment: p.description.clone().filter(|d| !d.is_empty()),
+            owner: p
+                .owner
+                .display_name
+                .clone()
+                .unwrap_or_else(|| p.owner.id.id().to_string()),
+            public: p.public.unwrap_or(true),
+            song_count: p.tracks.total,
+            duration: songs.iter().map(|s| s.duration).sum(),
+            created: added.clone().min().unwrap_or(now).to_rfc3339(),
+            changed: added.max().unwrap_or(now).to_rfc3339(),
+            cover_art: songs.first().and_then(|s| s.cover_art.clone()),
+            readonly: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistWithSongs {
+    #[serde(flatten)]
+    pub playlist: Playlist,
+    pub entry: Vec<Song>,
+}
+
+/// extracts the year from a spotify release date (`YYYY`, `YYYY-MM` or `YYYY-MM-DD`).
+fn release_year(date: &str) -> Option<i32> {
+    date.split('-').next()?.parse().ok()
+}
+
+/// a user as reported by `getUser` and `getUsers`. roles without a counterpart here are reported
+/// as subsonic's defaults for a new user.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInfo {
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub scrobbling_enabled: bool,
+    pub admin_role: bool,
+    pub settings_role: bool,
+    pub download_role: bool,
+    pub upload_role: bool,
+    pub playlist_role: bool,
+    pub cover_art_role: bool,
+    pub comment_role: bool,
+    pub podcast_role: bool,
+    pub stream_role: bool,
+    pub jukebox_role: bool,
+    pub share_role: bool,
+    pub video_conversion_role: bool,
+    pub folder: Vec<u32>,
+}
+
+impl UserInfo {
+    pub fn from_user(user: &User) -> Self {
+        Self {
+            username: user.username.clone(),
+            email: user.email.clone(),
+            scrobbling_enabled: false,
+            admin_role: user.roles.admin,
+            settings_role: true,
+            download_role: user.roles.download,
+            upload_role: false,
+            playlist_role: user.roles.playlist,
+            cover_art_role: false,
+            comment_role: false,
+            podcast_role: false,
+            stream_role: user.roles.stream,
+            jukebox_role: false,
+            share_role: user.roles.share,
+            video_conversion_role: false,
+            folder: Vec::new(),
+        }
+    }
+}
+
+/// an api key as reported by `createApiKey` and `getApiKeys`. the key itself is only known, and
+/// so only included, right after it was issued.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub created: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl ApiKeyInfo {
+    pub fn from_stored(api_key: &ApiKey, key: Option<String>) -> Self {
+        Self {
+            id: api_key.id.clone(),
+            username: api_key.username.clone(),
+            name: api_key.name.clone(),
+            created: api_key.created.to_rfc3339(),
+            key,
+        }
+    }
+}
@@ -1,12 +1,23 @@
 mod auth;
 mod consts;
+mod cover;
+mod flac;
 mod json;
+mod mp3;
 mod opus;
+mod pipeline;
+mod playlist;
 mod prelude;
+mod proxy;
+mod range;
 mod rate_limit;
 mod routes;
 mod sink;
 mod state;
+mod store;
+mod user;
+mod vorbis;
+mod wav;
 
 pub mod app;
 pub mod cfg;
@@ -0,0 +1,162 @@
+use mp3lame_encoder::{
+    Bitrate, Builder, Encoder, FlushGap, InterleavedPcm, Quality, max_required_buffer_size,
+};
+
+use crate::prelude::*;
+
+// the bitrates lame supports for mpeg-1 layer iii at 44100hz, ascending
+const MP3_BIT_RATES: [(u32, Bitrate); 13] = [
+    (32, Bitrate::Kbps32),
+    (40, Bitrate::Kbps40),
+    (48, Bitrate::Kbps48),
+    (64, Bitrate::Kbps64),
+    (80, Bitrate::Kbps80),
+    (96, Bitrate::Kbps96),
+    (112, Bitrate::Kbps112),
+    (128, Bitrate::Kbps128),
+    (160, Bitrate::Kbps160),
+    (192, Bitrate::Kbps192),
+    (224, Bitrate::Kbps224),
+    (256, Bitrate::Kbps256),
+    (320, Bitrate::Kbps320),
+];
+
+// lame needs at least this much room to flush its final frames
+const MP3_FLUSH_BUFFER_SIZE: usize = 7200;
+
+// id3v2.4, the first version allowing utf-8 text, see https://id3.org/id3v2.4.0-structure
+const ID3_VERSION: [u8; 2] = [4, 0];
+const ID3_HEADER_SIZE: usize = 10;
+const ID3_ENCODING_LATIN1: u8 = 0;
+const ID3_ENCODING_UTF8: u8 = 3;
+const ID3_PICTURE_FRONT_COVER: u8 = 3;
+
+/// an id3v2 tag holding `tags`, or nothing if there are none.
+fn id3_tag(tags: &TrackTags) -> Vec<u8> {
+    let mut frames = Vec::new();
+    let text = [
+        (b"TIT2", Some(tags.title.clone()).filter(|t| !t.is_empty())),
+        (b"TPE1", tags.artist.clone()),
+        (b"TALB", Some(tags.album.clone()).filter(|a| !a.is_empty())),
+        (b"TRCK", (tags.track > 0).then(|| tags.track.to_string())),
+        (b"TDRC", tags.date.clone()),
+        (b"TSRC", tags.isrc.clone()),
+    ];
+    for (id, value) in text {
+        if let Some(value) = value {
+            let mut body = vec![ID3_ENCODING_UTF8];
+            body.extend_from_slice(value.as_bytes());
+            id3_frame(&mut frames, id, &body);
+        }
+    }
+    if let Some(cover) = &tags.cover {
+        let mut body = vec![ID3_ENCODING_LATIN1];
+        body.extend_from_slice(cover_content_type(cover).as_bytes());
+        body.push(0);
+        body.push(ID3_PICTURE_FRONT_COVER);
+        body.push(0); // no description
+        body.extend_from_slice(cover);
+        id3_frame(&mut frames, b"APIC", &body);
+    }
+    if frames.is_empty() {
+        return frames;
+    }
+
+    let mut tag = Vec::with_capacity(ID3_HEADER_SIZE + frames.len());
+    tag.extend_from_slice(b"ID3");
+    tag.extend_from_slice(&ID3_VERSION);
+    tag.push(0); // no flags
+    tag.extend_from_slice(&syncsafe(frames.len()));
+    tag.extend(frames);
+    tag
+}
+
+fn id3_frame(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&syncsafe(body.len()));
+    out.extend_from_slice(&[0, 0]); // no flags
+    out.extend_from_slice(body);
+}
+
+/// a 28-bit size spread over four bytes with their top bits clear, as id3v2 sizes are stored.
+fn syncsafe(size: usize) -> [u8; 4] {
+    let size = size as u32;
+    [21, 14, 7, 0].map(|shift| ((size >> shift) & 0x7f) as u8)
+}
+
+/// the highest supported mp3 bitrate that doesn't exceed `bit_rate` (kbps).
+pub fn mp3_bit_rate(bit_rate: u32) -> u32 {
+    lame_bit_rate(bit_rate).0
+}
+
+fn lame_bit_rate(bit_rate: u32) -> (u32, Bitrate) {
+    MP3_BIT_RATES
+        .iter()
+        .rev()
+        .find(|(kbps, _)| *kbps <= bit_rate)
+        .copied()
+        .unwrap_or(MP3_BIT_RATES[0])
+}
+
+/// wraps a lame encoder producing a constant bitrate mp3 stream. mp3 frames are self-describing,
+/// so there is no header and the output can be cut anywhere on a frame boundary.
+pub struct Mp3Streamer {
+    encoder: Encoder,
+}
+
+impl Mp3Streamer {
+    pub fn new(settings: &EncoderSettings) -> Self {
+        let mut builder = Builder::new().expect("failed to create mp3 encoder");
+        builder
+            .set_num_channels(2)
+            .expect("failed to set mp3 channels");
+        builder
+            .set_sample_rate(SOURCE_SAMPLE_RATE)
+            .expect("failed to set mp3 sample rate");
+        builder
+            .set_brate(lame_bit_rate(settings.bit_rate).1)
+            .expect("failed to set mp3 bitrate");
+        // constant bitrate keeps duration and seek estimates exact for older clients
+        builder
+            .set_vbr_mode(mp3lame_encoder::VbrMode::Off)
+            .expect("failed to set mp3 vbr mode");
+        // a xing/lame tag would have to be patched into the first frame after encoding
+        builder
+            .set_to_write_vbr_tag(false)
+            .expect("failed to disable mp3 vbr tag");
+        builder
+            .set_quality(Quality::NearBest)
+            .expect("failed to set mp3 quality");
+
+        Self {
+            encoder: builder.build().expect("failed to build mp3 encoder"),
+        }
+    }
+}
+
+impl AudioEncoder for Mp3Streamer {
+    fn sample_rate(&self) -> u32 {
+        SOURCE_SAMPLE_RATE
+    }
+
+    /// an id3v2 tag, which players skip over to the first frame.
+    fn header_bytes(&mut self, tags: &TrackTags) -> Vec<u8> {
+        id3_tag(tags)
+    }
+
+    fn encode_chunk(&mut self, pcm: &[i16]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(max_required_buffer_size(pcm.len()));
+        self.encoder
+            .encode_to_vec(InterleavedPcm(pcm), &mut out)
+            .expect("mp3 encode failed");
+        out
+    }
+
+    fn flush(&mut self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MP3_FLUSH_BUFFER_SIZE);
+        self.encoder
+            .flush_to_vec::<FlushGap>(&mut out)
+            .expect("mp3 flush failed");
+        out
+    }
+}
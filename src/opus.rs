@@ -1,266 +1,319 @@
 use std::io::Cursor;
 
-use audiopus::{Application, Channels, SampleRate, coder::Encoder};
+use audiopus::{Application, Bitrate, Channels, SampleRate, coder::Encoder};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use ogg::writing::PacketWriteEndInfo;
-use rubato::{
-    Async, FixedAsync, Resampler, SincInterpolationParameters, SincInterpolationType,
-    WindowFunction, audioadapter::Adapter,
-};
+
+use crate::prelude::*;
+
+// opus always runs at 48kHz internally, and its granule positions count samples at that rate
+const OPUS_SAMPLE_RATE: u32 = 48000;
 
 // opus frame size at 48kHz — 20ms per frame, stereo interleaved
 const OPUS_FRAME_SIZE: usize = 960;
 const OPUS_FRAME_SAMPLES: usize = OPUS_FRAME_SIZE * 2;
 
-// number of samples to trim from the start to compensate for encoder algorithmic delay
-const OPUS_PRE_SKIP: u16 = 312;
-
-// resampler input chunk size — larger = more latency, smaller = more cpu
-const CHUNK_SIZE: usize = 960;
-
-/// drives pcm data from librespot (44100hz s16 stereo) through resampling and
-/// opus encoding into a valid ogg/opus bytestream.
-pub struct AudioPipeline {
-    pub encoder: OggOpusStreamer,
-    resampler: Async<f32>,
-    buffer_left: Vec<f32>,
-    buffer_right: Vec<f32>,
-    resample_left: Vec<f32>,
-    resample_right: Vec<f32>,
-}
-
-impl AudioPipeline {
-    pub fn new() -> Self {
-        let params = SincInterpolationParameters {
-            sinc_len: 64,
-            f_cutoff: 0.95,
-            interpolation: SincInterpolationType::Linear,
-            oversampling_factor: 128,
-            window: WindowFunction::BlackmanHarris2,
-        };
-
-        // resampler converts 44100hz (librespot output) → 48000hz (opus requirement)
-        let resampler = Async::<f32>::new_sinc(
-            48000.0 / 44100.0,
-            2.0,
-            &params,
-            CHUNK_SIZE,
-            2,
-            FixedAsync::Input,
-        )
-        .expect("failed to create resampler");
-
-        Self {
-            encoder: OggOpusStreamer::new(),
-            resampler,
-            buffer_left: Vec::with_capacity(CHUNK_SIZE * 2),
-            buffer_right: Vec::with_capacity(CHUNK_SIZE * 2),
-            resample_left: vec![0.0; CHUNK_SIZE],
-            resample_right: vec![0.0; CHUNK_SIZE],
-        }
-    }
-
-    /// resamples one chunk of left/right f32 samples and returns stereo interleaved i16.
-    fn resample_and_interleave(&mut self) -> Vec<i16> {
-        let input = vec![self.resample_left.clone(), self.resample_right.clone()];
-        let input_adapter =
-            audioadapter_buffers::direct::SequentialSliceOfVecs::new(&input, 2, CHUNK_SIZE)
-                .expect("failed to create input adapter");
-
-        let resampled = self
-            .resampler
-            .process(&input_adapter, 0, None)
-            .expect("resampling failed");
-
-        let frames = resampled.frames();
-        let mut interleaved = Vec::with_capacity(frames * 2);
-        for i in 0..frames {
-            let l = resampled.read_sample(0, i).unwrap_or(0.0);
-            let r = resampled.read_sample(1, i).unwrap_or(0.0);
-            // clamp before cast to avoid undefined behaviour on out-of-range f32 values
-            interleaved.push((l * 32767.0).clamp(-32768.0, 32767.0) as i16);
-            interleaved.push((r * 32767.0).clamp(-32768.0, 32767.0) as i16);
-        }
-        interleaved
-    }
-
-    /// accepts raw s16 stereo pcm, buffers internally, and returns any newly encoded ogg pages.
-    pub fn process(&mut self, pcm: &[i16]) -> Vec<u8> {
-        // deinterleave and normalise to f32 for the resampler
-        for pair in pcm.chunks_exact(2) {
-            self.buffer_left.push(pair[0] as f32 * (1.0 / 32768.0));
-            self.buffer_right.push(pair[1] as f32 * (1.0 / 32768.0));
-        }
-
-        let mut all_ogg = Vec::new();
-
-        while self.buffer_left.len() >= CHUNK_SIZE {
-            self.resample_left
-                .copy_from_slice(&self.buffer_left[..CHUNK_SIZE]);
-            self.resample_right
-                .copy_from_slice(&self.buffer_right[..CHUNK_SIZE]);
-
-            self.buffer_left.drain(..CHUNK_SIZE);
-            self.buffer_right.drain(..CHUNK_SIZE);
-
-            let interleaved = self.resample_and_interleave();
-            all_ogg.extend(self.encoder.encode_chunk(&interleaved));
-        }
-
-        all_ogg
-    }
-
-    /// flushes any remaining buffered samples and finalises the ogg stream.
-    pub fn flush(&mut self) -> Vec<u8> {
-        let mut out = Vec::new();
-
-        if !self.buffer_left.is_empty() {
-            // pad the final chunk to a full chunk size with silence
-            self.buffer_left.resize(CHUNK_SIZE, 0.0);
-            self.buffer_right.resize(CHUNK_SIZE, 0.0);
-
-            self.resample_left
-                .copy_from_slice(&self.buffer_left[..CHUNK_SIZE]);
-            self.resample_right
-                .copy_from_slice(&self.buffer_right[..CHUNK_SIZE]);
-            self.buffer_left.clear();
-            self.buffer_right.clear();
-
-            let interleaved = self.resample_and_interleave();
-            out.extend(self.encoder.encode_chunk(&interleaved));
-        }
-
-        out.extend(self.encoder.flush());
-        out
-    }
+// libopus' usual lookahead at 48kHz, should the encoder fail to report its own
+const OPUS_DEFAULT_PRE_SKIP: u16 = 312;
+
+// audio frames per ogg page after the first, which has one to itself. pages break at fixed frames
+// so a resumed stream can number its pages like the original. at most 6 lacing values per
+// packet, 40 frames always fit a page's 255.
+const OGG_PAGE_FRAMES: u64 = 40;
+
+// ogg page header: capture pattern through page segment count, and the fields we rewrite
+const OGG_PAGE_HEADER_SIZE: usize = 27;
+const OGG_HEADER_TYPE_BOS: u8 = 0x02;
+
+// bitrates (kbps) and complexity accepted by libopus for a stereo stream
+pub const OPUS_MIN_BIT_RATE: u32 = 6;
+pub const OPUS_MAX_BIT_RATE: u32 = 510;
+pub const OPUS_MAX_COMPLEXITY: u8 = 10;
+
+/// opus rate control. mp3 is always encoded at a constant bitrate.
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VbrMode {
+    /// constant bitrate
+    Cbr,
+    /// variable bitrate that never overshoots the target
+    Cvbr,
+    /// unconstrained variable bitrate, libopus' default
+    #[default]
+    Vbr,
 }
 
 /// wraps an opus encoder and ogg muxer, producing a valid streaming ogg/opus bytestream.
 pub struct OggOpusStreamer {
     encoder: Encoder,
     ogg_writer: ogg::writing::PacketWriter<'static, Cursor<Vec<u8>>>,
-    // granule position counts opus samples at 48kHz, used for seeking and sync
+    // granule position counts opus samples at 48kHz, used for seeking and sync. streams that
+    // start part way into a track begin at that position rather than 0.
     granule_pos: u64,
-    // random serial number identifies this logical ogg bitstream
+    // samples to trim from the start to compensate for the encoder's algorithmic delay
+    pre_skip: u16,
+    // granule position of the last real (not padding) sample, for trimming the final page
+    end_pos: u64,
+    // granule position the stream started at
+    start_pos: u64,
+    // audio frames written since the start, including any a resumed stream skipped
+    frame: u64,
+    // playback gain in db as q7.8 fixed point
+    output_gain: i16,
+    // identifies this logical ogg bitstream. derived from the track and settings, so a stream
+    // restarted part way through for a byte range continues the one the client has headers for
     serial: u32,
+    // pages in the headers, once written
+    header_pages: u32,
+    // set on a resumed stream: added to the writer's page sequence numbers, whose first page
+    // mustn't be flagged as beginning a stream either
+    resumed_seq: Option<u32>,
+    settings: EncoderSettings,
     // internal sample buffer to accumulate until a full opus frame is available
     buffer: Vec<i16>,
     encode_buf: Vec<u8>,
 }
 
 impl OggOpusStreamer {
-    pub fn new() -> Self {
-        let encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+    /// an encoder for a stream starting `start_ms` into the track, with `gain_db` to be applied
+    /// by decoders. `key` identifies the output, see [`EncoderSettings::cache_key`].
+    pub fn new(settings: &EncoderSettings, key: &str, start_ms: u64, gain_db: f64) -> Self {
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
             .expect("failed to create opus encoder");
 
+        // settings are clamped to libopus' accepted ranges when resolved, so these can't fail
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(settings.bit_rate as i32 * 1000))
+            .expect("failed to set opus bitrate");
+        encoder
+            .set_complexity(settings.complexity)
+            .expect("failed to set opus complexity");
+        encoder
+            .set_vbr(!matches!(settings.vbr, VbrMode::Cbr))
+            .expect("failed to set opus vbr");
+        encoder
+            .set_vbr_constraint(matches!(settings.vbr, VbrMode::Cvbr))
+            .expect("failed to set opus vbr constraint");
+
+        let pre_skip = encoder
+            .lookahead()
+            .ok()
+            .and_then(|n| u16::try_from(n).ok())
+            .unwrap_or(OPUS_DEFAULT_PRE_SKIP);
+        let start = start_ms * OPUS_SAMPLE_RATE as u64 / 1000;
+
         Self {
             encoder,
             ogg_writer: ogg::writing::PacketWriter::new(Cursor::new(Vec::new())),
-            granule_pos: 0,
-            serial: rand::random::<u32>(),
+            granule_pos: start,
+            pre_skip,
+            end_pos: start + pre_skip as u64,
+            start_pos: start,
+            frame: 0,
+            output_gain: (gain_db * 256.0)
+                .round()
+                .clamp(i16::MIN as f64, i16::MAX as f64) as i16,
+            serial: ogg_serial(key),
+            header_pages: 0,
+            resumed_seq: None,
+            settings: *settings,
             buffer: Vec::new(),
             // 4000 bytes is well above the maximum opus packet size
             encode_buf: vec![0u8; 4000],
         }
     }
 
+    /// encodes one full frame and writes it as a packet, ending the page as `end` says.
+    fn write_frame(&mut self, frame: &[i16], end: PacketWriteEndInfo) {
+        let len = self
+            .encoder
+            .encode(frame, &mut self.encode_buf)
+            .expect("opus encode failed");
+        self.granule_pos += OPUS_FRAME_SIZE as u64;
+
+        let (end, granule_pos) = match end {
+            // the last page's granule position trims the silence the final frames were padded
+            // with
+            PacketWriteEndInfo::EndStream => (end, self.end_pos),
+            // the first audio packet gets a page to itself, so a decoder can derive the stream's
+            // start time from that page's granule position without parsing packets
+            _ if self.frame.is_multiple_of(OGG_PAGE_FRAMES) => {
+                (PacketWriteEndInfo::EndPage, self.granule_pos)
+            }
+            _ => (end, self.granule_pos),
+        };
+        self.frame += 1;
+
+        self.ogg_writer
+            .write_packet(
+                self.encode_buf[..len].to_vec(),
+                self.serial,
+                end,
+                granule_pos,
+            )
+            .expect("failed to write ogg packet");
+    }
+
+    /// extracts all bytes written to the internal cursor and resets it for the next write.
+    fn take_ogg_bytes(&mut self) -> Vec<u8> {
+        let cursor = self.ogg_writer.inner_mut();
+        let mut bytes = cursor.get_ref().clone();
+        cursor.get_mut().clear();
+        cursor.set_position(0);
+
+        // the writer numbers pages from 0 and flags the first as a new stream, a resumed
+        // stream's pages carry on from the original instead
+        if let Some(seq_offset) = self.resumed_seq {
+            for page in ogg_pages(&mut bytes) {
+                page[5] &= !OGG_HEADER_TYPE_BOS;
+                let seq = u32::from_le_bytes(page[18..22].try_into().unwrap()) + seq_offset;
+                page[18..22].copy_from_slice(&seq.to_le_bytes());
+                page[22..26].fill(0);
+                let crc = ogg_crc(page);
+                page[22..26].copy_from_slice(&crc.to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+/// a stable ogg serial number for the output identified by `key`.
+fn ogg_serial(key: &str) -> u32 {
+    let digest = Md5::digest(key.as_bytes());
+    u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// splits a run of complete ogg pages into the individual pages.
+fn ogg_pages(mut bytes: &mut [u8]) -> Vec<&mut [u8]> {
+    let mut pages = Vec::new();
+    while bytes.len() >= OGG_PAGE_HEADER_SIZE {
+        let segments = bytes[26] as usize;
+        let body: usize = bytes[OGG_PAGE_HEADER_SIZE..OGG_PAGE_HEADER_SIZE + segments]
+            .iter()
+            .map(|&len| len as usize)
+            .sum();
+        let (page, rest) = bytes.split_at_mut(OGG_PAGE_HEADER_SIZE + segments + body);
+        pages.push(page);
+        bytes = rest;
+    }
+    pages
+}
+
+/// the ogg page checksum: crc-32 with polynomial 0x04c11db7, unreflected and starting from 0.
+fn ogg_crc(page: &[u8]) -> u32 {
+    page.iter().fold(0u32, |mut crc, &byte| {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+impl AudioEncoder for OggOpusStreamer {
+    fn sample_rate(&self) -> u32 {
+        OPUS_SAMPLE_RATE
+    }
+
     /// builds and returns the ogg/opus identification and comment headers.
-    pub fn header_bytes(&mut self) -> Vec<u8> {
+    fn header_bytes(&mut self, tags: &TrackTags) -> Vec<u8> {
         // OpusHead identification header — defined in rfc7845 §5.1
         let mut head = Vec::new();
         head.extend_from_slice(b"OpusHead");
         head.push(1); // version
         head.push(2); // channel count
-        head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
-        head.extend_from_slice(&48000u32.to_le_bytes()); // input sample rate (informational)
-        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.extend_from_slice(&self.pre_skip.to_le_bytes());
+        head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes()); // input sample rate (informational)
+        head.extend_from_slice(&self.output_gain.to_le_bytes());
         head.push(0); // channel mapping family (stereo)
 
         self.ogg_writer
             .write_packet(head, self.serial, PacketWriteEndInfo::EndPage, 0)
             .expect("failed to write OpusHead");
 
-        // OpusTags comment header — required by spec even if empty
-        let mut tags = Vec::new();
-        tags.extend_from_slice(b"OpusTags");
-        let vendor = env!("CARGO_PKG_NAME").as_bytes();
-        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
-        tags.extend_from_slice(vendor);
-        tags.extend_from_slice(&0u32.to_le_bytes()); // zero user comments
+        // OpusTags comment header — defined in rfc7845 §5.2, cover art goes in as a base64 flac
+        // picture block like in ogg vorbis
+        let mut comments = tags.vorbis_comments();
+        if let Some(cover) = &tags.cover {
+            let picture = BASE64.encode(picture_block(cover));
+            comments.push(format!("METADATA_BLOCK_PICTURE={picture}"));
+        }
+
+        let mut packet = Vec::new();
+        packet.extend_from_slice(b"OpusTags");
+        packet.extend(vorbis_comment_block(&comments));
 
         self.ogg_writer
-            .write_packet(tags, self.serial, PacketWriteEndInfo::EndPage, 0)
+            .write_packet(packet, self.serial, PacketWriteEndInfo::EndPage, 0)
             .expect("failed to write OpusTags");
 
-        self.take_ogg_bytes()
+        let mut bytes = self.take_ogg_bytes();
+        self.header_pages = ogg_pages(&mut bytes).len() as u32;
+        bytes
     }
 
     /// encodes interleaved s16 stereo pcm into ogg pages and returns any completed pages.
-    pub fn encode_chunk(&mut self, pcm: &[i16]) -> Vec<u8> {
+    fn encode_chunk(&mut self, pcm: &[i16]) -> Vec<u8> {
         self.buffer.extend_from_slice(pcm);
+        self.end_pos += (pcm.len() / 2) as u64;
 
         while self.buffer.len() >= OPUS_FRAME_SAMPLES {
             let frame: Vec<i16> = self.buffer.drain(..OPUS_FRAME_SAMPLES).collect();
-            let len = self
-                .encoder
-                .encode(&frame, &mut self.encode_buf)
-                .expect("opus encode failed");
-
-            self.granule_pos += OPUS_FRAME_SIZE as u64;
-
-            self.ogg_writer
-                .write_packet(
-                    self.encode_buf[..len].to_vec(),
-                    self.serial,
-                    PacketWriteEndInfo::NormalPacket,
-                    self.granule_pos,
-                )
-                .expect("failed to write ogg packet");
+            self.write_frame(&frame, PacketWriteEndInfo::NormalPacket);
         }
 
         self.take_ogg_bytes()
     }
 
     /// encodes any remaining buffered samples and writes the ogg end-of-stream marker.
-    pub fn flush(&mut self) -> Vec<u8> {
-        if !self.buffer.is_empty() {
-            // pad the final frame with silence so the encoder receives a full frame
-            self.buffer.resize(OPUS_FRAME_SAMPLES, 0);
-            let frame = std::mem::take(&mut self.buffer);
-            let len = self
-                .encoder
-                .encode(&frame, &mut self.encode_buf)
-                .expect("opus encode failed during flush");
-
-            self.granule_pos += OPUS_FRAME_SIZE as u64;
-
-            self.ogg_writer
-                .write_packet(
-                    self.encode_buf[..len].to_vec(),
-                    self.serial,
-                    PacketWriteEndInfo::EndStream,
-                    self.granule_pos,
-                )
-                .expect("failed to write final ogg packet");
-        } else {
-            // stream was frame-aligned — still need EndStream for a valid bitstream
-            self.ogg_writer
-                .write_packet(
-                    vec![],
-                    self.serial,
-                    PacketWriteEndInfo::EndStream,
-                    self.granule_pos,
-                )
-                .expect("failed to write ogg end-of-stream marker");
+    fn flush(&mut self) -> Vec<u8> {
+        // the encoder lags its input by the pre-skip, so feed it that much silence to get the
+        // last real samples out, padded to whole frames
+        let padded = (self.buffer.len() + self.pre_skip as usize * 2).div_ceil(OPUS_FRAME_SAMPLES)
+            * OPUS_FRAME_SAMPLES;
+        self.buffer.resize(padded, 0);
+
+        let frames: Vec<Vec<i16>> = std::mem::take(&mut self.buffer)
+            .chunks(OPUS_FRAME_SAMPLES)
+            .map(<[i16]>::to_vec)
+            .collect();
+        let last = frames.len() - 1;
+        for (i, frame) in frames.iter().enumerate() {
+            let end = if i == last {
+                PacketWriteEndInfo::EndStream
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            self.write_frame(frame, end);
         }
         self.take_ogg_bytes()
     }
 
-    /// extracts all bytes written to the internal cursor and resets it for the next write.
-    fn take_ogg_bytes(&mut self) -> Vec<u8> {
-        let cursor = self.ogg_writer.inner_mut();
-        let bytes = cursor.get_ref().clone();
-        cursor.get_mut().clear();
-        cursor.set_position(0);
-        bytes
+    /// continues this stream's serial, timestamps and page numbering from a whole frame, without
+    /// a fresh encoder's headers. the decoder keeps applying the original pre-skip, and the new
+    /// encoder's warm-up is heard only as a few milliseconds of glitch at the seek point.
+    fn resume(&self, skip_ms: u64) -> Option<(Box<dyn AudioEncoder>, u64)> {
+        let frames = skip_ms * OPUS_SAMPLE_RATE as u64 / 1000 / OPUS_FRAME_SIZE as u64;
+        let page = if frames == 0 {
+            0
+        } else {
+            1 + (frames - 1) / OGG_PAGE_FRAMES
+        };
+
+        let mut resumed = Self::new(&self.settings, "", 0, 0.0);
+        resumed.serial = self.serial;
+        resumed.pre_skip = self.pre_skip;
+        resumed.start_pos = self.start_pos + frames * OPUS_FRAME_SIZE as u64;
+        resumed.granule_pos = resumed.start_pos;
+        resumed.end_pos = resumed.start_pos + self.pre_skip as u64;
+        resumed.frame = frames;
+        resumed.resumed_seq = Some(self.header_pages + page as u32);
+
+        let skipped_ms = frames * OPUS_FRAME_SIZE as u64 * 1000 / OPUS_SAMPLE_RATE as u64;
+        Some((Box::new(resumed), skipped_ms))
     }
 }
@@ -0,0 +1,527 @@
+use rubato::{
+    Async, FixedAsync, Resampler, SincInterpolationParameters, SincInterpolationType,
+    WindowFunction, audioadapter::Adapter,
+};
+
+use crate::prelude::*;
+
+// librespot always decodes to 44100hz s16 stereo
+pub const SOURCE_SAMPLE_RATE: u32 = 44100;
+
+// uncompressed bitrate of the source pcm in kbps, reported for lossless formats
+const LOSSLESS_BIT_RATE: u32 = SOURCE_SAMPLE_RATE * 16 * 2 / 1000;
+
+// bytes per second of the source pcm, which is also what wav streams carry
+const PCM_BYTE_RATE: u64 = SOURCE_SAMPLE_RATE as u64 * 2 * 2;
+
+// headroom on size estimates for variable bitrate output, so streams are padded rather than
+// cut short
+const VBR_SIZE_MARGIN_PERCENT: u64 = 5;
+
+// resampler input chunk size — larger = more latency, smaller = more cpu
+const CHUNK_SIZE: usize = 960;
+
+/// a streaming encoder fed interleaved s16 stereo pcm at [`AudioEncoder::sample_rate`].
+pub trait AudioEncoder {
+    /// the input rate the encoder expects. anything other than [`SOURCE_SAMPLE_RATE`] makes the
+    /// pipeline resample.
+    fn sample_rate(&self) -> u32;
+
+    /// container headers that must precede any audio, carrying `tags` where the format has room
+    /// for them.
+    fn header_bytes(&mut self, tags: &TrackTags) -> Vec<u8>;
+
+    /// encodes interleaved pcm and returns any completed output.
+    fn encode_chunk(&mut self, pcm: &[i16]) -> Vec<u8>;
+
+    /// encodes anything still buffered and terminates the stream.
+    fn flush(&mut self) -> Vec<u8>;
+
+    /// fills in headers written before the length was known, given the output's total length in
+    /// bytes. only possible where the output is stored rather than sent as it's made.
+    fn finish_header(&self, _header: &mut [u8], _len: u64) {}
+
+    /// an encoder continuing this one's output `skip_ms` further into the track, for a client
+    /// that already has the headers, along with the skip actually used. `None` where a fresh
+    /// encoder's output can simply be appended, as for formats that resync on every frame.
+    fn resume(&self, _skip_ms: u64) -> Option<(Box<dyn AudioEncoder>, u64)> {
+        None
+    }
+}
+
+/// output formats selectable through the subsonic `format` parameter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// spotify's own ogg vorbis, forwarded without transcoding
+    Raw,
+    #[default]
+    Opus,
+    Mp3,
+    Flac,
+    Wav,
+}
+
+impl OutputFormat {
+    /// parses a `format` value. unknown formats yield `None`, leaving the configured default in
+    /// charge.
+    pub fn from_param(format: &str) -> Option<Self> {
+        match format {
+            "raw" => Some(Self::Raw),
+            "opus" => Some(Self::Opus),
+            "mp3" => Some(Self::Mp3),
+            "flac" => Some(Self::Flac),
+            "wav" => Some(Self::Wav),
+            _ => None,
+        }
+    }
+
+    pub const fn suffix(self) -> &'static str {
+        match self {
+            Self::Raw => "ogg",
+            Self::Opus => "opus",
+            Self::Mp3 => "mp3",
+            Self::Flac => "flac",
+            Self::Wav => "wav",
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Raw => "audio/ogg",
+            Self::Opus => "audio/ogg; codecs=opus",
+            Self::Mp3 => "audio/mpeg",
+            Self::Flac => "audio/flac",
+            Self::Wav => "audio/wav",
+        }
+    }
+
+    /// limits a requested bitrate (kbps) to what the codec can actually produce. raw streams pick
+    /// among spotify's vorbis encodes, while lossless formats ignore it and report the source's
+    /// pcm bitrate instead.
+    pub fn clamp_bit_rate(self, bit_rate: u32) -> u32 {
+        match self {
+            Self::Raw => vorbis_bit_rate(bit_rate),
+            Self::Opus => bit_rate.clamp(OPUS_MIN_BIT_RATE, OPUS_MAX_BIT_RATE),
+            Self::Mp3 => mp3_bit_rate(bit_rate),
+            Self::Flac | Self::Wav => LOSSLESS_BIT_RATE,
+        }
+    }
+
+    /// bytes per second of encoded audio at `bit_rate` (kbps), as used for size estimates and for
+    /// mapping byte offsets back to time.
+    pub const fn byte_rate(self, bit_rate: u32) -> u64 {
+        match self {
+            Self::Wav => PCM_BYTE_RATE,
+            _ => bit_rate as u64 * 1000 / 8,
+        }
+    }
+
+    /// estimated size of `secs` seconds of audio, excluding headers. `None` where the size can't
+    /// be predicted, i.e. for flac, whose compression ratio depends entirely on the material.
+    pub const fn estimated_size(self, bit_rate: u32, secs: u64) -> Option<u64> {
+        let size = secs * self.byte_rate(bit_rate);
+        match self {
+            Self::Flac => None,
+            Self::Opus => Some(size * (100 + VBR_SIZE_MARGIN_PERCENT) / 100),
+            Self::Raw | Self::Mp3 | Self::Wav => Some(size),
+        }
+    }
+
+    /// alignment of byte offsets into the audio that a stream restarted at a later time must keep,
+    /// e.g. whole sample frames for wav.
+    pub const fn frame_align(self) -> u64 {
+        match self {
+            Self::Wav => 4,
+            _ => 1,
+        }
+    }
+
+    /// whether the format's headers carry [`TrackTags`].
+    pub const fn has_tags(self) -> bool {
+        matches!(self, Self::Opus | Self::Mp3 | Self::Flac)
+    }
+
+    /// whether the format's headers carry a playback gain, sparing the pipeline from scaling
+    /// samples.
+    pub const fn has_header_gain(self) -> bool {
+        matches!(self, Self::Opus)
+    }
+
+    fn encoder(
+        self,
+        settings: &EncoderSettings,
+        key: &str,
+        start_ms: u64,
+        gain_db: f64,
+    ) -> Box<dyn AudioEncoder> {
+        match self {
+            Self::Raw => unreachable!("raw streams bypass the pipeline"),
+            Self::Opus => Box::new(OggOpusStreamer::new(settings, key, start_ms, gain_db)),
+            Self::Mp3 => Box::new(Mp3Streamer::new(settings)),
+            Self::Flac => Box::new(FlacStreamer::new()),
+            Self::Wav => Box::new(WavStreamer),
+        }
+    }
+}
+
+/// which of spotify's loudness normalisation gains to apply, if any.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalisationMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+impl NormalisationMode {
+    /// the gain in db to apply to a track, lowered where needed so its peak doesn't clip.
+    pub fn gain_db(self, replay_gain: Option<&ReplayGain>) -> f64 {
+        let Some(rg) = replay_gain else {
+            return 0.0;
+        };
+        let (gain, peak) = match self {
+            Self::Off => return 0.0,
+            Self::Track => (rg.track_gain, rg.track_peak),
+            Self::Album => (rg.album_gain, rg.album_peak),
+        };
+        if peak > 0.0 {
+            (gain as f64).min(-20.0 * (peak as f64).log10())
+        } else {
+            gain as f64
+        }
+    }
+}
+
+/// encoder tuning for a single stream, see [`TranscodeConfig::encoder_settings`].
+#[derive(Clone, Copy, Debug)]
+pub struct EncoderSettings {
+    pub format: OutputFormat,
+    /// target bitrate in kbps, already clamped for `format`
+    pub bit_rate: u32,
+    /// 0 (fastest) to 10 (best quality)
+    pub complexity: u8,
+    pub vbr: VbrMode,
+    pub normalisation: NormalisationMode,
+}
+
+impl EncoderSettings {
+    /// identifies a finished transcode of track `id` with these settings.
+    pub fn cache_key(&self, id: &str) -> String {
+        format!(
+            "{id}-{}k-c{}-{:?}-{:?}.{}",
+            self.bit_rate,
+            self.complexity,
+            self.vbr,
+            self.normalisation,
+            self.format.suffix()
+        )
+    }
+}
+
+/// track metadata written into the output's headers.
+#[derive(Clone, Debug, Default)]
+pub struct TrackTags {
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: String,
+    pub track: u32,
+    /// release date, `YYYY`, `YYYY-MM` or `YYYY-MM-DD`
+    pub date: Option<String>,
+    pub isrc: Option<String>,
+    /// front cover image
+    pub cover: Option<Bytes>,
+}
+
+impl TrackTags {
+    pub fn new(song: &Song, cover: Option<Bytes>) -> Self {
+        Self {
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            track: song.track,
+            date: song
+                .created
+                .clone()
+                .or_else(|| song.year.map(|y| y.to_string())),
+            isrc: song.isrc.first().cloned(),
+            cover,
+        }
+    }
+
+    /// the text tags as vorbis comments (`FIELD=value`), as used by ogg and flac.
+    pub fn vorbis_comments(&self) -> Vec<String> {
+        let mut comments = Vec::new();
+        if !self.title.is_empty() {
+            comments.push(format!("TITLE={}", self.title));
+        }
+        if let Some(artist) = &self.artist {
+            comments.push(format!("ARTIST={artist}"));
+        }
+        if !self.album.is_empty() {
+            comments.push(format!("ALBUM={}", self.album));
+        }
+        if self.track > 0 {
+            comments.push(format!("TRACKNUMBER={}", self.track));
+        }
+        if let Some(date) = &self.date {
+            comments.push(format!("DATE={date}"));
+        }
+        if let Some(isrc) = &self.isrc {
+            comments.push(format!("ISRC={isrc}"));
+        }
+        comments
+    }
+}
+
+/// the body of a vorbis comment header: our name as the vendor, then `comments`, each prefixed
+/// with its little-endian length.
+pub fn vorbis_comment_block(comments: &[String]) -> Vec<u8> {
+    let mut block = Vec::new();
+    let vendor = env!("CARGO_PKG_NAME").as_bytes();
+    block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    block.extend_from_slice(vendor);
+    block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        block.extend_from_slice(comment.as_bytes());
+    }
+    block
+}
+
+/// drives pcm data from librespot (44100hz s16 stereo) through resampling, if the encoder needs
+/// it, and encoding into the selected output format.
+pub struct AudioPipeline {
+    encoder: Box<dyn AudioEncoder>,
+    resampler: Option<PcmResampler>,
+    // linear gain applied to samples, for formats that can't carry it in their headers
+    gain: Option<f32>,
+    // what the encoder was made from, to make a fresh one on resuming
+    settings: EncoderSettings,
+    key: String,
+    start_ms: u64,
+    gain_db: f64,
+}
+
+impl AudioPipeline {
+    /// a pipeline for a stream starting `start_ms` into the track, which formats with timestamps
+    /// carry over into their output, with its loudness adjusted by `gain_db`. `key` identifies
+    /// the output, see [`EncoderSettings::cache_key`].
+    pub fn new(settings: EncoderSettings, key: &str, start_ms: u64, gain_db: f64) -> Self {
+        let encoder = settings.format.encoder(&settings, key, start_ms, gain_db);
+        let resampler = (encoder.sample_rate() != SOURCE_SAMPLE_RATE)
+            .then(|| PcmResampler::new(encoder.sample_rate()));
+        let gain = (gain_db != 0.0 && !settings.format.has_header_gain())
+            .then(|| 10f64.powf(gain_db / 20.0) as f32);
+
+        Self {
+            encoder,
+            resampler,
+            gain,
+            settings,
+            key: key.to_string(),
+            start_ms,
+            gain_db,
+        }
+    }
+
+    /// restarts the stream about `skip_ms` further into the track, for a client that already has
+    /// the headers. returns how far it actually skipped, which playback must start from.
+    pub fn resume(&mut self, skip_ms: u64) -> u64 {
+        let (encoder, skipped_ms) = self.encoder.resume(skip_ms).unwrap_or_else(|| {
+            let start_ms = self.start_ms + skip_ms;
+            let encoder =
+                self.settings
+                    .format
+                    .encoder(&self.settings, &self.key, start_ms, self.gain_db);
+            (encoder, skip_ms)
+        });
+        self.encoder = encoder;
+        self.resampler = self
+            .resampler
+            .as_ref()
+            .map(|_| PcmResampler::new(self.encoder.sample_rate()));
+        self.start_ms += skipped_ms;
+        skipped_ms
+    }
+
+    pub fn header_bytes(&mut self, tags: &TrackTags) -> Vec<u8> {
+        self.encoder.header_bytes(tags)
+    }
+
+    /// accepts raw s16 stereo pcm, buffers internally, and returns any newly encoded output.
+    pub fn process(&mut self, pcm: &[i16]) -> Vec<u8> {
+        let scaled: Vec<i16>;
+        let pcm = match self.gain {
+            Some(gain) => {
+                // the gain is capped by the track's peak, the clamp only catches rounding
+                scaled = pcm
+                    .iter()
+                    .map(|&s| (s as f32 * gain).clamp(-32768.0, 32767.0) as i16)
+                    .collect();
+                &scaled
+            }
+            None => pcm,
+        };
+
+        match &mut self.resampler {
+            Some(resampler) => {
+                let resampled = resampler.process(pcm);
+                self.encoder.encode_chunk(&resampled)
+            }
+            None => self.encoder.encode_chunk(pcm),
+        }
+    }
+
+    /// see [`AudioEncoder::finish_header`].
+    pub fn finish_header(&self, header: &mut [u8], len: u64) {
+        self.encoder.finish_header(header, len);
+    }
+
+    /// flushes any remaining buffered samples and finalises the stream.
+    pub fn flush(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(resampler) = &mut self.resampler {
+            let resampled = resampler.flush();
+            out.extend(self.encoder.encode_chunk(&resampled));
+        }
+        out.extend(self.encoder.flush());
+        out
+    }
+}
+
+/// converts librespot's 44100hz output to the rate an encoder requires. the resampler's own delay
+/// is trimmed, so output lines up with the input sample for sample.
+struct PcmResampler {
+    resampler: Async<f32>,
+    sample_rate: u32,
+    // leading output frames still to drop
+    delay: usize,
+    frames_in: u64,
+    frames_out: u64,
+    buffer_left: Vec<f32>,
+    buffer_right: Vec<f32>,
+    resample_left: Vec<f32>,
+    resample_right: Vec<f32>,
+}
+
+impl PcmResampler {
+    fn new(sample_rate: u32) -> Self {
+        let params = SincInterpolationParameters {
+            sinc_len: 64,
+            f_cutoff: 0.95,
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 128,
+            window: WindowFunction::BlackmanHarris2,
+        };
+
+        let resampler = Async::<f32>::new_sinc(
+            sample_rate as f64 / SOURCE_SAMPLE_RATE as f64,
+            2.0,
+            &params,
+            CHUNK_SIZE,
+            2,
+            FixedAsync::Input,
+        )
+        .expect("failed to create resampler");
+
+        Self {
+            delay: resampler.output_delay(),
+            resampler,
+            sample_rate,
+            frames_in: 0,
+            frames_out: 0,
+            buffer_left: Vec::with_capacity(CHUNK_SIZE * 2),
+            buffer_right: Vec::with_capacity(CHUNK_SIZE * 2),
+            resample_left: vec![0.0; CHUNK_SIZE],
+            resample_right: vec![0.0; CHUNK_SIZE],
+        }
+    }
+
+    /// resamples one chunk of left/right f32 samples and returns stereo interleaved i16.
+    fn resample_and_interleave(&mut self) -> Vec<i16> {
+        let input = vec![self.resample_left.clone(), self.resample_right.clone()];
+        let input_adapter =
+            audioadapter_buffers::direct::SequentialSliceOfVecs::new(&input, 2, CHUNK_SIZE)
+                .expect("failed to create input adapter");
+
+        let resampled = self
+            .resampler
+            .process(&input_adapter, 0, None)
+            .expect("resampling failed");
+
+        let frames = resampled.frames();
+        let mut interleaved = Vec::with_capacity(frames * 2);
+        for i in 0..frames {
+            let l = resampled.read_sample(0, i).unwrap_or(0.0);
+            let r = resampled.read_sample(1, i).unwrap_or(0.0);
+            // clamp before cast to avoid undefined behaviour on out-of-range f32 values
+            interleaved.push((l * 32767.0).clamp(-32768.0, 32767.0) as i16);
+            interleaved.push((r * 32767.0).clamp(-32768.0, 32767.0) as i16);
+        }
+        interleaved
+    }
+
+    /// resamples one chunk, dropping whatever is left of the resampler's delay.
+    fn resample_chunk(&mut self) -> Vec<i16> {
+        let mut out = self.resample_and_interleave();
+        let trim = self.delay.min(out.len() / 2);
+        out.drain(..trim * 2);
+        self.delay -= trim;
+        self.frames_out += (out.len() / 2) as u64;
+        out
+    }
+
+    /// buffers s16 stereo pcm and returns whatever full chunks could be resampled.
+    fn process(&mut self, pcm: &[i16]) -> Vec<i16> {
+        self.frames_in += (pcm.len() / 2) as u64;
+
+        // deinterleave and normalise to f32 for the resampler
+        for pair in pcm.chunks_exact(2) {
+            self.buffer_left.push(pair[0] as f32 * (1.0 / 32768.0));
+            self.buffer_right.push(pair[1] as f32 * (1.0 / 32768.0));
+        }
+
+        let mut out = Vec::new();
+
+        while self.buffer_left.len() >= CHUNK_SIZE {
+            self.resample_left
+                .copy_from_slice(&self.buffer_left[..CHUNK_SIZE]);
+            self.resample_right
+                .copy_from_slice(&self.buffer_right[..CHUNK_SIZE]);
+
+            self.buffer_left.drain(..CHUNK_SIZE);
+            self.buffer_right.drain(..CHUNK_SIZE);
+
+            out.extend(self.resample_chunk());
+        }
+
+        out
+    }
+
+    /// resamples whatever is left, pushing silence through until the output covers exactly as
+    /// long as the input did.
+    fn flush(&mut self) -> Vec<i16> {
+        let expected = self.frames_in * self.sample_rate as u64 / SOURCE_SAMPLE_RATE as u64;
+
+        let mut out = Vec::new();
+        while self.frames_out < expected {
+            self.buffer_left.resize(CHUNK_SIZE, 0.0);
+            self.buffer_right.resize(CHUNK_SIZE, 0.0);
+
+            self.resample_left
+                .copy_from_slice(&self.buffer_left[..CHUNK_SIZE]);
+            self.resample_right
+                .copy_from_slice(&self.buffer_right[..CHUNK_SIZE]);
+            self.buffer_left.clear();
+            self.buffer_right.clear();
+
+            out.extend(self.resample_chunk());
+        }
+
+        let excess = self.frames_out.saturating_sub(expected) as usize;
+        out.truncate(out.len().saturating_sub(excess * 2));
+        out
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+use crate::prelude::*;
+
+/// a server-side playlist, persisted as spotify track ids.
+#[derive(Clone, Debug, Serialize, serde::Deserialize)]
+pub struct StoredPlaylist {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    pub comment: Option<String>,
+    pub public: bool,
+    pub created: DateTime<Utc>,
+    pub changed: DateTime<Utc>,
+    pub entries: Vec<String>,
+}
+
+impl StoredPlaylist {
+    pub fn touch(&mut self) {
+        self.changed = Utc::now();
+    }
+
+    /// whether `user` may change or delete the playlist.
+    pub fn editable_by(&self, user: &User) -> bool {
+        user.roles.admin || self.owner == user.username
+    }
+
+    /// whether `user` may see the playlist.
+    pub fn visible_to(&self, user: &User) -> bool {
+        self.public || self.editable_by(user)
+    }
+}
+
+#[derive(Clone, Default, Serialize, serde::Deserialize)]
+struct PlaylistFile {
+    next_id: u64,
+    playlists: Vec<StoredPlaylist>,
+}
+
+/// json-file backed playlist storage, rewritten in full on every change.
+pub struct PlaylistStore {
+    path: PathBuf,
+    file: PlaylistFile,
+}
+
+impl PlaylistStore {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = match std::fs::File::open(&path) {
+            Ok(rdr) => serde_json::from_reader(rdr)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, file })
+    }
+
+    /// applies `f` to a copy of the store and persists it, only keeping the change once it's
+    /// saved.
+    fn change<T>(&mut self, f: impl FnOnce(&mut PlaylistFile) -> T) -> Result<T> {
+        let mut file = self.file.clone();
+        let result = f(&mut file);
+        // write to a sibling file first so a crash never leaves a truncated store behind
+        let tmp = self.path.with_extension("json.tmp");
+        serde_json::to_writer_pretty(std::fs::File::create(&tmp)?, &file)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = file;
+        Ok(result)
+    }
+
+    pub fn all(&self) -> &[StoredPlaylist] {
+        &self.file.playlists
+    }
+
+    pub fn get(&self, id: &str) -> Option<&StoredPlaylist> {
+        self.file.playlists.iter().find(|p| p.id == id)
+    }
+
+    pub fn create(&mut self, name: String, owner: String, entries: Vec<String>) -> Result<String> {
+        self.change(|file| {
+            file.next_id += 1;
+            let id = format!("{PLAYLIST_ID_PREFIX}{}", file.next_id);
+            let now = Utc::now();
+
+            file.playlists.push(StoredPlaylist {
+                id: id.clone(),
+                name,
+                owner,
+                comment: None,
+                public: false,
+                created: now,
+                changed: now,
+                entries,
+            });
+            id
+        })
+    }
+
+    /// applies `f` to the playlist and persists the result. returns `None` if it doesn't exist.
+    pub fn update<F: FnOnce(&mut StoredPlaylist)>(&mut self, id: &str, f: F) -> Option<Result<()>> {
+        let pos = self.file.playlists.iter().position(|p| p.id == id)?;
+        Some(self.change(|file| {
+            let playlist = &mut file.playlists[pos];
+            f(playlist);
+            playlist.touch();
+        }))
+    }
+
+    pub fn delete(&mut self, id: &str) -> Option<Result<()>> {
+        let pos = self.file.playlists.iter().position(|p| p.id == id)?;
+        Some(self.change(|file| {
+            file.playlists.remove(pos);
+        }))
+    }
+}
@@ -1,11 +1,13 @@
 pub use std::collections::HashMap;
 pub use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+pub use std::sync::Arc;
 pub use std::time::Duration;
 
 // error-handling
 pub use anyhow::{Result, anyhow};
 
 // actix
+pub use actix_web::http::header;
 pub use actix_web::web::{Data, Query};
 pub use actix_web::{HttpRequest, HttpResponse, Responder};
 pub use bytes::Bytes;
@@ -14,26 +16,30 @@ pub use bytes::Bytes;
 pub use librespot::core::{Session, SessionConfig, SpotifyId, SpotifyUri, cache::Cache};
 pub use librespot::discovery::Credentials as LSpotCreds;
 pub use librespot::playback::{
-    audio_backend::{Sink, SinkResult},
+    audio_backend::{Sink, SinkError, SinkResult},
     config::AudioFormat,
     convert::Converter,
     decoder::AudioPacket,
     mixer::NoOpVolume,
-    player::Player,
+    player::{Player, PlayerEvent},
 };
 
 // rspotify
 pub use rspotify::ClientCredsSpotify as RSpotify;
-pub use rspotify::model::{AlbumId, FullTrack, Id, SearchResult, SearchType, TrackId};
+pub use rspotify::model::{
+    AlbumId, AlbumType, ArtistId, FullAlbum, FullArtist, FullPlaylist, FullTrack, Id, PlayableItem,
+    PlaylistId, SearchType, SimplifiedAlbum, SimplifiedArtist, SimplifiedTrack, TrackId,
+};
 pub use rspotify::prelude::BaseClient;
 
 // misc
 pub use md5::{Digest, Md5};
 pub use reqwest::Client as HttpClient;
 pub use serde::Serialize;
+pub use sha2::Sha256;
 pub use tokio::sync::{
     Mutex,
-    mpsc::{UnboundedSender, unbounded_channel},
+    mpsc::{Sender, channel},
 };
 pub use tokio::time::sleep;
 pub use zerocopy::IntoBytes;
@@ -42,9 +48,20 @@ pub use zerocopy::IntoBytes;
 pub use crate::auth::*;
 pub use crate::cfg::*;
 pub use crate::consts::*;
+pub use crate::cover::*;
+pub use crate::flac::*;
 pub use crate::json::*;
+pub use crate::mp3::*;
 pub use crate::opus::*;
+pub use crate::pipeline::*;
+pub use crate::playlist::*;
+pub use crate::proxy::*;
+pub use crate::range::*;
 pub use crate::rate_limit::*;
 pub use crate::routes::*;
 pub use crate::sink::*;
 pub use crate::state::*;
+pub use crate::store::*;
+pub use crate::user::*;
+pub use crate::vorbis::*;
+pub use crate::wav::*;
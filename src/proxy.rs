@@ -0,0 +1,295 @@
+use crate::prelude::*;
+
+/// an address range in cidr notation, e.g. `10.0.0.0/8`. a bare address is a range of one.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 clients may show up as ipv4-mapped ipv6 addresses on dual-stack sockets
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.as_str(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in '{s}'"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("invalid prefix length in '{s}'"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// the address of the client behind any trusted proxies. requests straight from a trusted proxy
+/// are attributed to the last untrusted hop in their `Forwarded` or `X-Forwarded-For` header, and
+/// everything else to the peer itself.
+pub fn client_ip(req: &HttpRequest, proxy: &ProxyConfig) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !proxy.is_trusted(peer) {
+        return Some(peer);
+    }
+
+    // each proxy appends the address it received the request from, so the client is the
+    // rightmost hop that isn't one of ours
+    let hops = forwarded_for(req);
+    let client = hops
+        .iter()
+        .rev()
+        .find(|ip| !proxy.is_trusted(**ip))
+        .or(hops.first())
+        .copied();
+    Some(client.unwrap_or(peer))
+}
+
+/// the username a trusted proxy authenticated the request as, if header authentication is on.
+pub fn proxy_user<'a>(req: &'a HttpRequest, proxy: &ProxyConfig) -> Option<&'a str> {
+    let header = proxy.user_header()?;
+    if !proxy.is_trusted(req.peer_addr()?.ip()) {
+        return None;
+    }
+    req.headers()
+        .get(header)?
+        .to_str()
+        .ok()
+        .map(str::trim)
+        .filter(|u| !u.is_empty())
+}
+
+/// the hops listed by `Forwarded` (rfc 7239), or failing that `X-Forwarded-For`, in order.
+/// obfuscated or unparsable entries are skipped.
+fn forwarded_for(req: &HttpRequest) -> Vec<IpAddr> {
+    let forwarded: Vec<IpAddr> = req
+        .headers()
+        .get_all(header::FORWARDED)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim_matches('"')))
+                    .flatten()
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    req.headers()
+        .get_all(header::X_FORWARDED_FOR)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+/// parses a forwarded node: an address, optionally with a port, ipv6 ones in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn range(s: &str) -> IpRange {
+        IpRange::try_from(s.to_string()).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn proxy(trusted: &[&str]) -> ProxyConfig {
+        serde_json::from_value(serde_json::json!({ "trusted_proxies": trusted })).unwrap()
+    }
+
+    fn request(peer: &str, headers: &[(header::HeaderName, &str)]) -> HttpRequest {
+        let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+        for (name, value) in headers {
+            req = req.append_header((name.clone(), *value));
+        }
+        req.to_http_request()
+    }
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(range("10.0.0.0/8").prefix, 8);
+        assert_eq!(range("10.1.2.3").prefix, 32);
+        assert_eq!(range("::1").prefix, 128);
+        assert_eq!(range("fd00::/8").prefix, 8);
+        assert!(IpRange::try_from("10.0.0.0/33".to_string()).is_err());
+        assert!(IpRange::try_from("fd00::/129".to_string()).is_err());
+        assert!(IpRange::try_from("10.0.0.0/".to_string()).is_err());
+        assert!(IpRange::try_from("10.0.0.0/x".to_string()).is_err());
+        assert!(IpRange::try_from("10.0.0/8".to_string()).is_err());
+        assert!(IpRange::try_from("localhost".to_string()).is_err());
+    }
+
+    #[test]
+    fn range_contains() {
+        let all = range("0.0.0.0/0");
+        assert!(all.contains(ip("0.0.0.0")));
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(!all.contains(ip("::1")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+
+        let one = range("192.168.1.10/32");
+        assert!(one.contains(ip("192.168.1.10")));
+        assert!(!one.contains(ip("192.168.1.11")));
+
+        let net = range("10.0.0.0/8");
+        assert!(net.contains(ip("10.255.0.1")));
+        assert!(!net.contains(ip("11.0.0.1")));
+
+        let v6 = range("fd00::/8");
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn range_contains_mapped() {
+        let net = range("10.0.0.0/8");
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("::ffff:11.1.2.3")));
+        // only ipv4-mapped addresses are unwrapped, not ipv4-compatible ones
+        assert!(!net.contains(ip("::10.1.2.3")));
+    }
+
+    #[test]
+    fn forwarded_nodes() {
+        let req = request(
+            "10.0.0.1:443",
+            &[(
+                header::FORWARDED,
+                "for=192.0.2.60;proto=https, For=\"[2001:db8::1]:4711\", for=\"198.51.100.7:8080\", for=_hidden, for=unknown",
+            )],
+        );
+        assert_eq!(
+            forwarded_for(&req),
+            [ip("192.0.2.60"), ip("2001:db8::1"), ip("198.51.100.7")]
+        );
+
+        // x-forwarded-for is only read without a forwarded header
+        let req = request(
+            "10.0.0.1:443",
+            &[
+                (header::X_FORWARDED_FOR, "203.0.113.1"),
+                (header::FORWARDED, "for=192.0.2.60"),
+            ],
+        );
+        assert_eq!(forwarded_for(&req), [ip("192.0.2.60")]);
+
+        let req = request(
+            "10.0.0.1:443",
+            &[(
+                header::X_FORWARDED_FOR,
+                "203.0.113.1, [2001:db8::2]:80, bogus",
+            )],
+        );
+        assert_eq!(forwarded_for(&req), [ip("203.0.113.1"), ip("2001:db8::2")]);
+    }
+
+    #[test]
+    fn client_behind_trusted_proxies() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+        let req = request(
+            "10.0.0.1:443",
+            &[(header::X_FORWARDED_FOR, "203.0.113.1, 10.0.0.2")],
+        );
+        assert_eq!(client_ip(&req, &proxy), Some(ip("203.0.113.1")));
+
+        // a proxy that only lists trusted hops leaves the first as the client
+        let req = request(
+            "10.0.0.1:443",
+            &[(header::X_FORWARDED_FOR, "10.0.0.3, 10.0.0.2")],
+        );
+        assert_eq!(client_ip(&req, &proxy), Some(ip("10.0.0.3")));
+
+        let req = request("10.0.0.1:443", &[]);
+        assert_eq!(client_ip(&req, &proxy), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn spoofed_forwarding_ignored() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+
+        // a client that isn't a trusted proxy can't claim to be someone else
+        let req = request(
+            "203.0.113.1:5000",
+            &[
+                (header::X_FORWARDED_FOR, "198.51.100.7"),
+                (header::FORWARDED, "for=198.51.100.7"),
+            ],
+        );
+        assert_eq!(client_ip(&req, &proxy), Some(ip("203.0.113.1")));
+
+        // nor can it hide behind a proxy by prepending hops, which the proxy appends to
+        let req = request(
+            "10.0.0.1:443",
+            &[(header::X_FORWARDED_FOR, "198.51.100.7, 203.0.113.1")],
+        );
+        assert_eq!(client_ip(&req, &proxy), Some(ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn proxy_user_needs_trusted_peer() {
+        let proxy: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "trusted_proxies": ["10.0.0.1"],
+            "user_header": "Remote-User",
+        }))
+        .unwrap();
+        let name = header::HeaderName::from_static("remote-user");
+
+        let req = request("10.0.0.1:443", &[(name.clone(), " alice ")]);
+        assert_eq!(proxy_user(&req, &proxy), Some("alice"));
+
+        let req = request("203.0.113.1:5000", &[(name.clone(), "alice")]);
+        assert_eq!(proxy_user(&req, &proxy), None);
+
+        let req = request("10.0.0.1:443", &[(name, "")]);
+        assert_eq!(proxy_user(&req, &proxy), None);
+    }
+}
@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+use actix_web::body::SizedStream;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, ContentRange, ContentRangeSpec, Range};
+use futures_util::{Stream, StreamExt};
+
+use crate::prelude::*;
+
+/// what a `Range` header asks of an entity of a known total length.
+pub enum RangeRequest {
+    /// no usable range (absent, multi-range or a unit other than bytes), serve everything
+    Full,
+    /// bytes `start..=end`
+    Partial(u64, u64),
+    /// the range lies beyond the end of the entity
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    pub fn from_request(req: &HttpRequest, total: u64) -> Self {
+        let Some(value) = req.headers().get(header::RANGE) else {
+            return Self::Full;
+        };
+        let spec = match value.to_str().ok().and_then(|v| Range::from_str(v).ok()) {
+            Some(Range::Bytes(specs)) if specs.len() == 1 => specs[0].clone(),
+            _ => return Self::Full,
+        };
+        match spec.to_satisfiable_range(total) {
+            Some((start, end)) => Self::Partial(start, end),
+            None => Self::Unsatisfiable,
+        }
+    }
+
+    /// the first byte to send and the number of bytes to send.
+    pub const fn window(&self, total: u64) -> (u64, u64) {
+        match *self {
+            Self::Partial(start, end) => (start, end - start + 1),
+            Self::Full | Self::Unsatisfiable => (0, total),
+        }
+    }
+}
+
+/// a `200` or `206` response whose body is exactly the length promised in its headers. the
+/// stream's first `skip` bytes are dropped, and it is cut off or padded with zeros to fit, since
+/// transcoded sizes are only estimates.
+pub fn sized_response<S>(
+    content_type: &str,
+    range: &RangeRequest,
+    total: u64,
+    skip: u64,
+    stream: S,
+) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, actix_web::Error>> + Unpin + 'static,
+{
+    let (start, len) = range.window(total);
+
+    let mut res = match range {
+        RangeRequest::Partial(start, end) => {
+            let mut res = HttpResponse::PartialContent();
+            res.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((*start, *end)),
+                instance_length: Some(total),
+            }));
+            res
+        }
+        RangeRequest::Full | RangeRequest::Unsatisfiable => HttpResponse::Ok(),
+    };
+    log::debug!("Sending bytes {start}+{len} of ~{total}.");
+
+    let to_end = start + len == total;
+    res.content_type(content_type)
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .body(SizedStream::new(len, fit_stream(stream, skip, len, to_end)))
+}
+
+/// `416` for a range past the end of an entity of `total` bytes.
+pub fn unsatisfiable_response(total: u64) -> HttpResponse {
+    HttpResponse::build(StatusCode::RANGE_NOT_SATISFIABLE)
+        .insert_header(ContentRange(ContentRangeSpec::Bytes {
+            range: None,
+            instance_length: Some(total),
+        }))
+        .finish()
+}
+
+/// sends `len` bytes of `stream` after skipping `skip`. `to_end` says the window reaches the end
+/// of the estimated total, so any output beyond it is the track's end being cut off.
+fn fit_stream<S>(
+    mut stream: S,
+    mut skip: u64,
+    len: u64,
+    to_end: bool,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    S: Stream<Item = Result<Bytes, actix_web::Error>> + Unpin + 'static,
+{
+    async_stream::stream! {
+        let mut remaining = len;
+        let mut cut = false;
+        while remaining > 0 {
+            let Some(chunk) = stream.next().await else {
+                break;
+            };
+            let mut chunk = chunk?;
+
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            let chunk = chunk.split_off(skipped as usize);
+
+            let take = remaining.min(chunk.len() as u64);
+            remaining -= take;
+            cut = take < chunk.len() as u64;
+            if take > 0 {
+                yield Ok(chunk.slice(..take as usize));
+            }
+        }
+        if to_end && remaining == 0 && !cut {
+            cut = stream.next().await.is_some();
+        }
+        if to_end && cut {
+            log::warn!("Stream exceeded its estimated length of {len} bytes, cutting off the end.");
+        }
+        // stop the encoder as soon as enough has been sent
+        drop(stream);
+
+        if remaining > 0 {
+            log::debug!("Padding stream with {remaining} bytes.");
+            // the missing tail can be large, so it's sent as slices of one zeroed chunk
+            let zeros = Bytes::from(vec![0u8; CACHED_READ_SIZE]);
+            while remaining > 0 {
+                let take = remaining.min(CACHED_READ_SIZE as u64);
+                remaining -= take;
+                yield Ok(zeros.slice(..take as usize));
+            }
+        }
+    }
+}
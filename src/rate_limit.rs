@@ -1,20 +1,146 @@
-const RATE_LIMIT: u8 = 10;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Instant;
 
+use crate::prelude::*;
+
+// how often idle entries are dropped from the limiter
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// what failed logins are counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Ip(IpAddr),
+    User(String),
+}
+
+impl fmt::Display for RateLimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "address {ip}"),
+            Self::User(user) => write!(f, "user {user}"),
+        }
+    }
+}
+
+/// the failed logins of one key within the window, and the lockout they caused.
 #[derive(Default)]
-pub struct RateLimit {
-    count: u8,
+struct RateLimit {
+    failures: VecDeque<Instant>,
+    locked_until: Option<Instant>,
 }
 
 impl RateLimit {
-    pub fn incr(&mut self) -> bool {
-        if self.count > RATE_LIMIT {
-            return false;
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.is_some_and(|until| now < until)
+    }
+
+    /// forgets failures that left the window and lockouts that ran out.
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while self
+            .failures
+            .front()
+            .is_some_and(|&t| now.duration_since(t) >= window)
+        {
+            self.failures.pop_front();
+        }
+        if !self.is_locked(now) {
+            self.locked_until = None;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.failures.is_empty() && self.locked_until.is_none()
+    }
+}
+
+/// counts failed logins per address and per username over a sliding window, locking a key out
+/// for a while once it has too many. entries are dropped once their failures and lockout expire,
+/// and past `max_entries` the least recently failed one makes room.
+pub struct RateLimiter {
+    cfg: RateLimitConfig,
+    limits: HashMap<RateLimitKey, RateLimit>,
+    last_prune: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: RateLimitConfig) -> Self {
+        Self {
+            cfg,
+            limits: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// whether any of the keys is locked out.
+    pub fn is_locked(&mut self, keys: &[RateLimitKey]) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_prune) >= PRUNE_INTERVAL {
+            self.prune(now);
+        }
+        keys.iter()
+            .any(|key| self.limits.get(key).is_some_and(|l| l.is_locked(now)))
+    }
+
+    /// records a failed login against each key, locking out those over their limit.
+    pub fn fail(&mut self, keys: &[RateLimitKey]) {
+        let now = Instant::now();
+        for key in keys {
+            let max_failures = match key {
+                RateLimitKey::Ip(_) => self.cfg.ip_max_failures(),
+                RateLimitKey::User(_) => self.cfg.user_max_failures(),
+            };
+            // a limit of 0 turns limiting off for that kind of key
+            if max_failures == 0 {
+                continue;
+            }
+            if !self.limits.contains_key(key) && self.limits.len() >= self.cfg.max_entries() {
+                self.make_room(now);
+            }
+
+            let limit = self.limits.entry(key.clone()).or_default();
+            limit.expire(now, self.cfg.window());
+            limit.failures.push_back(now);
+            if limit.failures.len() >= max_failures as usize {
+                limit.failures.clear();
+                limit.locked_until = Some(now + self.cfg.lockout());
+                log::warn!(
+                    "Locking out {key} for {}s after {max_failures} failed logins.",
+                    self.cfg.lockout().as_secs()
+                );
+            }
         }
-        self.count += 1;
-        true
     }
 
-    pub fn reset(&mut self) {
-        self.count = 0
+    /// forgets the failures of a user who just logged in. those of their address are left to
+    /// expire, or one valid account would let it keep guessing at others.
+    pub fn succeed(&mut self, username: &str) {
+        self.limits
+            .remove(&RateLimitKey::User(username.to_string()));
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let window = self.cfg.window();
+        self.limits.retain(|_, limit| {
+            limit.expire(now, window);
+            !limit.is_idle()
+        });
+        self.last_prune = now;
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        self.prune(now);
+        if self.limits.len() < self.cfg.max_entries() {
+            return;
+        }
+        // unlocked keys go first, then those whose last failure is oldest
+        let evict = self
+            .limits
+            .iter()
+            .min_by_key(|(_, l)| (l.is_locked(now), l.failures.back().copied()))
+            .map(|(key, _)| key.clone());
+        if let Some(key) = evict {
+            self.limits.remove(&key);
+        }
     }
 }
@@ -1,56 +1,729 @@
+use std::io::SeekFrom;
+
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
 use crate::prelude::*;
 
-pub async fn get_cover_art(
+pub async fn change_password(
     req: HttpRequest,
     data: Data<State>,
     query: Query<HashMap<String, String>>,
 ) -> impl Responder {
-    if !verify(req, &data, &query).await {
-        log::error!("get_cover_art: Unauthorized.");
-        return HttpResponse::Unauthorized().finish();
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("change_password: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+
+    let (username, password) = match (query.get("username"), query.get("password")) {
+        (Some(username), Some(password)) => (username, password),
+        _ => return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query),
+    };
+    let password = match decode_password(password) {
+        Some(password) => password,
+        None => {
+            return ResponseBody::<()>::error_with(
+                ErrorCode::Generic,
+                "Invalid hex-encoded password",
+            )
+            .into_response(&query);
+        }
+    };
+
+    // users may change their own password, admins anyone's
+    if !user.roles.admin && user.username != *username {
+        log::error!("change_password: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let mut users = data.users().lock().await;
+    if users.is_builtin(username) {
+        return ResponseBody::<()>::error_with(
+            ErrorCode::NotAuthorized,
+            "The configured account can only be changed in the config file",
+        )
+        .into_response(&query);
+    }
+    match users.update(username, |u| u.password = Some(password)) {
+        Some(Ok(())) => ResponseBody::<()>::ok().into_response(&query),
+        Some(Err(e)) => {
+            log::error!("change_password: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+        None => ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    }
+}
+
+pub async fn create_api_key(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("create_api_key: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+
+    // users may issue keys to themselves, admins to anyone
+    let username = query.get("username").unwrap_or(&user.username);
+    if !user.roles.admin && user.username != *username {
+        log::error!("create_api_key: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let mut users = data.users().lock().await;
+    if users.get(username).is_none() {
+        return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query);
+    }
+    match users.issue_key(username, query.get("name").cloned()) {
+        Ok((api_key, key)) => ResponseBody::ok_with(serde_json::json!({
+            "apiKey": ApiKeyInfo::from_stored(&api_key, Some(key))
+        }))
+        .into_response(&query),
+        Err(e) => {
+            log::error!("create_api_key: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+    }
+}
+
+pub async fn create_playlist(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let song_ids = query_all(&req, "songId");
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("create_playlist: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.playlist {
+        log::error!("create_playlist: {} may not edit playlists.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let mut playlists = data.playlists().lock().await;
+
+    // with `playlistId` the existing playlist's songs are replaced, otherwise one is created
+    let id = if let Some(id) = query.get("playlistId") {
+        if !id.starts_with(PLAYLIST_ID_PREFIX) {
+            return ResponseBody::<()>::error_with(
+                ErrorCode::NotAuthorized,
+                "Spotify playlists are read-only",
+            )
+            .into_response(&query);
+        }
+        if playlists.get(id).is_some_and(|p| !p.editable_by(&user)) {
+            return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+        }
+        let name = query.get("name").cloned();
+        match playlists.update(id, |p| {
+            p.entries = song_ids;
+            if let Some(name) = name {
+                p.name = name;
+            }
+        }) {
+            Some(Ok(())) => id.clone(),
+            Some(Err(e)) => {
+                log::error!("create_playlist: {e}");
+                return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query);
+            }
+            None => return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+        }
+    } else if let Some(name) = query.get("name") {
+        match playlists.create(name.clone(), user.username.clone(), song_ids) {
+            Ok(id) => id,
+            Err(e) => {
+                log::error!("create_playlist: {e}");
+                return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query);
+            }
+        }
+    } else {
+        return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+    };
+
+    let playlist = match playlists.get(&id) {
+        Some(p) => p.clone(),
+        None => return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query),
+    };
+    drop(playlists);
+
+    let mut songs = match data.songs(&playlist.entries).await {
+        Ok(songs) => songs,
+        Err(_) => return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query),
+    };
+    report_transcoding(&data, &user, &query, &mut songs);
+
+    ResponseBody::ok_with(serde_json::json!({
+        "playlist": PlaylistWithSongs {
+            playlist: Playlist::from_stored(&playlist, &songs),
+            entry: songs,
+        }
+    }))
+    .into_response(&query)
+}
+
+pub async fn create_user(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("create_user: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.admin {
+        log::error!("create_user: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let username = match query.get("username") {
+        Some(username) => username,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    // without a password the account can only sign in with api keys issued to it
+    let password = match query.get("password").map(|p| decode_password(p)) {
+        Some(None) => {
+            return ResponseBody::<()>::error_with(
+                ErrorCode::Generic,
+                "Invalid hex-encoded password",
+            )
+            .into_response(&query);
+        }
+        password => password.flatten(),
+    };
+
+    // subsonic lets new users stream unless told otherwise, everything else is opt-in
+    let new_user = User {
+        username: username.clone(),
+        password,
+        email: query.get("email").cloned(),
+        roles: Roles {
+            admin: query_flag(&query, "adminRole").unwrap_or(false),
+            stream: query_flag(&query, "streamRole").unwrap_or(true),
+            download: query_flag(&query, "downloadRole").unwrap_or(false),
+            playlist: query_flag(&query, "playlistRole").unwrap_or(false),
+            share: query_flag(&query, "shareRole").unwrap_or(false),
+        },
+    };
+
+    match data.users().lock().await.create(new_user) {
+        Some(Ok(())) => ResponseBody::<()>::ok().into_response(&query),
+        Some(Err(e)) => {
+            log::error!("create_user: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+        None => ResponseBody::<()>::error_with(ErrorCode::Generic, "User already exists")
+            .into_response(&query),
+    }
+}
+
+pub async fn delete_api_key(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("delete_api_key: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+
+    let id = match query.get("id") {
+        Some(id) => id,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let mut users = data.users().lock().await;
+    if users
+        .get_key(id)
+        .is_some_and(|k| !user.roles.admin && k.username != user.username)
+    {
+        log::error!("delete_api_key: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+    match users.revoke_key(id) {
+        Some(Ok(())) => ResponseBody::<()>::ok().into_response(&query),
+        Some(Err(e)) => {
+            log::error!("delete_api_key: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+        None => ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    }
+}
+
+pub async fn delete_playlist(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("delete_playlist: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.playlist {
+        log::error!("delete_playlist: {} may not edit playlists.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
     }
 
     let id = match query.get("id") {
         Some(id) => id,
-        None => return ResponseBody::<()>::failed().into_response(),
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
     };
 
-    // return cached image to avoid redundant spotify cdn fetches
-    let image_bytes = if let Some(bytes) = data.cover_cache().lock().await.get(id) {
-        bytes.clone()
+    if !id.starts_with(PLAYLIST_ID_PREFIX) {
+        return ResponseBody::<()>::error_with(
+            ErrorCode::NotAuthorized,
+            "Spotify playlists are read-only",
+        )
+        .into_response(&query);
+    }
+
+    let mut playlists = data.playlists().lock().await;
+    if playlists.get(id).is_some_and(|p| !p.editable_by(&user)) {
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+    match playlists.delete(id) {
+        Some(Ok(())) => ResponseBody::<()>::ok().into_response(&query),
+        Some(Err(e)) => {
+            log::error!("delete_playlist: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+        None => ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    }
+}
+
+pub async fn delete_user(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("delete_user: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.admin {
+        log::error!("delete_user: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let username = match query.get("username") {
+        Some(username) => username,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let mut users = data.users().lock().await;
+    if users.is_builtin(username) {
+        return ResponseBody::<()>::error_with(
+            ErrorCode::NotAuthorized,
+            "The configured account can only be changed in the config file",
+        )
+        .into_response(&query);
+    }
+    match users.delete(username) {
+        Some(Ok(())) => ResponseBody::<()>::ok().into_response(&query),
+        Some(Err(e)) => {
+            log::error!("delete_user: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+        None => ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    }
+}
+
+pub async fn download(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req.clone(), &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("download: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.download {
+        log::error!("download: {} may not download.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let id = match query.get("id") {
+        Some(id) => id,
+        None => {
+            log::error!("download: Missing 'id'.");
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let uri = match SpotifyId::from_base62(id) {
+        Ok(id) => SpotifyUri::Track { id },
+        Err(..) => {
+            log::error!("download: Invalid 'id'.");
+            return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query);
+        }
+    };
+
+    let settings = encoder_settings(&data, &user, &query);
+    let sess = match data.session().await {
+        Ok(sess) => sess,
+        Err(e) => {
+            log::error!("session: {e}");
+            return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query);
+        }
+    };
+    let mut song = song_metadata(&data, id).await;
+
+    // downloads always have an exact length: raw ones are spotify's own files, and transcodes
+    // are only sent once they are complete on disk
+    let res = if settings.format == OutputFormat::Raw {
+        log::info!("Downloading {uri} as raw vorbis...");
+        stream_raw(&req, &sess, uri, 0, settings).await
     } else {
-        let album_id = match AlbumId::from_id(id) {
-            Ok(id) => id,
-            Err(_) => return HttpResponse::BadRequest().body("Invalid Spotify album ID"),
-        };
+        let cache_key = settings.cache_key(id);
+        match data.transcode_cache().get(&cache_key).await {
+            Some((file, len)) => {
+                log::info!(
+                    "Downloading {uri} as {} from cache...",
+                    settings.format.suffix()
+                );
+                stream_cached(&req, file, len, settings).await
+            }
+            None => {
+                log::info!(
+                    "Downloading {} as {} ({}kbps)...",
+                    uri,
+                    settings.format.suffix(),
+                    settings.bit_rate
+                );
+                if let Some(song) = &mut song {
+                    load_replay_gain(&data, &sess, song).await;
+                }
+                let tags = match &song {
+                    Some(song) => track_tags(&data, song, settings.format).await,
+                    None => TrackTags::default(),
+                };
+                let gain_db = settings
+                    .normalisation
+                    .gain_db(song.as_ref().and_then(|s| s.replay_gain.as_ref()));
+                match transcode(&data, sess, uri, settings, &tags, gain_db, &cache_key).await {
+                    Ok((file, len)) => stream_cached(&req, file, len, settings).await,
+                    Err(body) => Err(body),
+                }
+            }
+        }
+    };
 
-        let album = match data.rspotify().album(album_id, None).await {
-            Ok(t) => t,
-            Err(_) => return HttpResponse::InternalServerError().finish(),
-        };
+    match res {
+        Ok(mut res) => {
+            let disposition = download_disposition(song.as_ref(), id, settings.format);
+            if let Ok(value) = disposition.to_string().try_into() {
+                res.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+            }
+            res
+        }
+        Err(body) => body.into_response(&query),
+    }
+}
 
-        // spotify returns images sorted largest first
-        let image_url = match album.images.first() {
-            Some(img) => &img.url,
-            None => return HttpResponse::NotFound().finish(),
-        };
+pub async fn get_album(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("get_album: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
 
-        let bytes = match data.http().get(image_url).send().await {
-            Ok(resp) => match resp.bytes().await {
-                Ok(bytes) => bytes,
-                Err(_) => return HttpResponse::InternalServerError().finish(),
-            },
-            Err(_) => return HttpResponse::InternalServerError().finish(),
-        };
-        data.cover_cache()
-            .lock()
+    let id = match query.get("id") {
+        Some(id) => id,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let album_id = match AlbumId::from_id(id) {
+        Ok(id) => id,
+        Err(_) => {
+            return ResponseBody::<()>::error_with(ErrorCode::NotFound, "Invalid Spotify album ID")
+                .into_response(&query);
+        }
+    };
+
+    let album = match data.rspotify().album(album_id.as_ref(), None).await {
+        Ok(a) => a,
+        Err(_) => return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    };
+
+    // the album object only embeds the first page of tracks
+    let mut tracks = album.tracks.items.clone();
+    while (tracks.len() as u32) < album.tracks.total {
+        match data
+            .rspotify()
+            .album_track_manual(album_id.as_ref(), None, Some(50), Some(tracks.len() as u32))
             .await
-            .insert(id.clone(), bytes.clone());
-        bytes
+        {
+            Ok(page) if !page.items.is_empty() => tracks.extend(page.items),
+            Ok(_) => break,
+            Err(_) => return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query),
+        }
+    }
+
+    let mut songs: Vec<Song> = tracks
+        .iter()
+        .filter_map(|t| Song::from_album_track(t, &album))
+        .collect();
+
+    data.cache_songs(&mut songs).await;
+    report_transcoding(&data, &user, &query, &mut songs);
+
+    ResponseBody::ok_with(serde_json::json!({
+        "album": AlbumWithSongs {
+            album: Album::from_spotify(&album, &songs),
+            song: songs,
+        }
+    }))
+    .into_response(&query)
+}
+
+pub async fn get_api_keys(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("get_api_keys: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+
+    let username = query.get("username").unwrap_or(&user.username);
+    if !user.roles.admin && user.username != *username {
+        log::error!("get_api_keys: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let keys: Vec<ApiKeyInfo> = data
+        .users()
+        .lock()
+        .await
+        .keys(username)
+        .iter()
+        .map(|k| ApiKeyInfo::from_stored(k, None))
+        .collect();
+
+    ResponseBody::ok_with(serde_json::json!({ "apiKeys": { "apiKey": keys } }))
+        .into_response(&query)
+}
+
+pub async fn get_artist(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    if let Err(code) = verify(req, &data, &query).await {
+        log::error!("get_artist: Unauthorized.");
+        return ResponseBody::<()>::error(code).into_response(&query);
+    }
+
+    let id = match query.get("id") {
+        Some(id) => id,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let artist_id = match ArtistId::from_id(id) {
+        Ok(id) => id,
+        Err(_) => {
+            return ResponseBody::<()>::error_with(
+                ErrorCode::NotFound,
+                "Invalid Spotify artist ID",
+            )
+            .into_response(&query);
+        }
+    };
+
+    let artist = match data.rspotify().artist(artist_id.as_ref()).await {
+        Ok(a) => a,
+        Err(_) => return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    };
+
+    // the whole discography, page by page — singles included, appearances/compilations excluded
+    let mut albums = Vec::new();
+    loop {
+        match data
+            .rspotify()
+            .artist_albums_manual(
+                artist_id.as_ref(),
+                [AlbumType::Album, AlbumType::Single],
+                None,
+                Some(50),
+                Some(albums.len() as u32),
+            )
+            .await
+        {
+            Ok(page) => {
+                let done = page.items.is_empty() || page.next.is_none();
+                albums.extend(page.items);
+                if done {
+                    break;
+                }
+            }
+            Err(_) => return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query),
+        }
+    }
+
+    let album: Vec<Album> = albums.iter().filter_map(Album::from_simplified).collect();
+    let mut artist = Artist::from_spotify(&artist);
+    artist.album_count = Some(album.len() as u32);
+
+    data.artist_cache()
+        .insert(artist.id.clone(), artist.clone())
+        .await;
+
+    ResponseBody::ok_with(serde_json::json!({
+        "artist": ArtistWithAlbums { artist, album }
+    }))
+    .into_response(&query)
+}
+
+pub async fn get_artist_info2(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    if let Err(code) = verify(req, &data, &query).await {
+        log::error!("get_artist_info2: Unauthorized.");
+        return ResponseBody::<()>::error(code).into_response(&query);
+    }
+
+    let id = match query.get("id") {
+        Some(id) => id,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let artist_id = match ArtistId::from_id(id) {
+        Ok(id) => id,
+        Err(_) => {
+            return ResponseBody::<()>::error_with(
+                ErrorCode::NotFound,
+                "Invalid Spotify artist ID",
+            )
+            .into_response(&query);
+        }
+    };
+
+    let artist = match data.rspotify().artist(artist_id).await {
+        Ok(a) => a,
+        Err(_) => return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    };
+
+    // spotify returns images sorted largest first
+    let image_url = |i: usize| {
+        artist
+            .images
+            .get(i)
+            .or(artist.images.last())
+            .map(|img| &img.url)
+    };
+
+    ResponseBody::ok_with(serde_json::json!({
+        "artistInfo2": {
+            "smallImageUrl": image_url(2),
+            "mediumImageUrl": image_url(1),
+            "largeImageUrl": image_url(0),
+        }
+    }))
+    .into_response(&query)
+}
+
+pub async fn get_artists(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    if let Err(code) = verify(req, &data, &query).await {
+        log::error!("get_artists: Unauthorized.");
+        return ResponseBody::<()>::error(code).into_response(&query);
+    }
+
+    // spotify has no notion of a library, so list every artist seen so far
+    let artists = data.artist_cache().values().await;
+
+    ResponseBody::ok_with(serde_json::json!({
+        "artists": {
+            "ignoredArticles": IGNORED_ARTICLES,
+            "index": ArtistIndex::group(artists)
+        }
+    }))
+    .into_response(&query)
+}
+
+pub async fn get_cover_art(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    if let Err(code) = verify(req, &data, &query).await {
+        log::error!("get_cover_art: Unauthorized.");
+        return ResponseBody::<()>::error(code).into_response(&query);
+    }
+
+    let id = match query.get("id") {
+        Some(id) => id,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let size = cover_size_bucket(query.get("size").and_then(|s| s.parse::<u32>().ok()));
+
+    let image_bytes = match cover_art(&data, id, size).await {
+        Ok(bytes) => bytes,
+        Err(body) => return body.into_response(&query),
     };
 
     HttpResponse::Ok()
-        .insert_header(("Content-Type", "image/jpeg"))
+        .insert_header(("Content-Type", cover_content_type(&image_bytes)))
         .insert_header(("Cache-Control", "public, max-age=86400"))
         .body(image_bytes)
 }
@@ -60,26 +733,139 @@ pub async fn get_license(
     data: Data<State>,
     query: Query<HashMap<String, String>>,
 ) -> impl Responder {
-    if !verify(req, &data, &query).await {
+    if let Err(code) = verify(req, &data, &query).await {
         log::error!("get_license: Unauthorized.");
-        return HttpResponse::Unauthorized().finish();
+        return ResponseBody::<()>::error(code).into_response(&query);
     }
     ResponseBody::ok_with(serde_json::json!({
         "license": { "valid": true }
     }))
-    .into_response()
+    .into_response(&query)
 }
 
-pub async fn get_open_subsonic_extensions() -> impl Responder {
+pub async fn get_open_subsonic_extensions(query: Query<HashMap<String, String>>) -> impl Responder {
     ResponseBody::ok_with(serde_json::json!({
         "openSubsonicExtensions": [
             {
                 "name": "transcodeOffset",
                 "versions": [1]
+            },
+            {
+                "name": "apiKeyAuthentication",
+                "versions": [1]
             }
         ]
     }))
-    .into_response()
+    .into_response(&query)
+}
+
+pub async fn get_playlist(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("get_playlist: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+
+    let id = match query.get("id") {
+        Some(id) => id,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let playlist = if id.starts_with(PLAYLIST_ID_PREFIX) {
+        // other users' private playlists are treated as if they didn't exist
+        let playlist = match data.playlists().lock().await.get(id) {
+            Some(p) if p.visible_to(&user) => p.clone(),
+            _ => return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+        };
+
+        let mut songs = match data.songs(&playlist.entries).await {
+            Ok(songs) => songs,
+            Err(_) => return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query),
+        };
+        report_transcoding(&data, &user, &query, &mut songs);
+
+        PlaylistWithSongs {
+            playlist: Playlist::from_stored(&playlist, &songs),
+            entry: songs,
+        }
+    } else {
+        let (playlist, mut songs) = match fetch_spotify_playlist(&data, id, true).await {
+            Ok(p) => p,
+            Err(e) => {
+                log::error!("get_playlist: {e}");
+                return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query);
+            }
+        };
+        report_transcoding(&data, &user, &query, &mut songs);
+
+        PlaylistWithSongs {
+            playlist: Playlist::from_spotify(&playlist, &songs),
+            entry: songs,
+        }
+    };
+
+    ResponseBody::ok_with(serde_json::json!({ "playlist": playlist })).into_response(&query)
+}
+
+pub async fn get_playlists(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("get_playlists: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+
+    let stored: Vec<StoredPlaylist> = data
+        .playlists()
+        .lock()
+        .await
+        .all()
+        .iter()
+        .filter(|p| p.visible_to(&user))
+        .cloned()
+        .collect();
+
+    let mut playlists = Vec::with_capacity(stored.len() + data.spotify_playlists().len());
+    for playlist in &stored {
+        // listings only need totals, so don't hit spotify for uncached songs
+        let mut songs = Vec::with_capacity(playlist.entries.len());
+        for id in &playlist.entries {
+            songs.extend(data.song_cache().get(id).await);
+        }
+        playlists.push(Playlist::from_stored(playlist, &songs));
+    }
+
+    // imports are listed from the cache where possible, so polling clients don't hit spotify
+    for id in data.spotify_playlists() {
+        if let Some(playlist) = data.playlist_cache().get(id).await {
+            playlists.push(playlist);
+            continue;
+        }
+        match fetch_spotify_playlist(&data, id, false).await {
+            Ok((playlist, songs)) => playlists.push(Playlist::from_spotify(&playlist, &songs)),
+            Err(e) => log::error!("get_playlists: spotify playlist '{id}': {e}"),
+        }
+    }
+
+    ResponseBody::ok_with(serde_json::json!({
+        "playlists": {
+            "playlist": playlists
+        }
+    }))
+    .into_response(&query)
 }
 
 pub async fn get_song(
@@ -87,43 +873,114 @@ pub async fn get_song(
     data: Data<State>,
     query: Query<HashMap<String, String>>,
 ) -> impl Responder {
-    if !verify(req, &data, &query).await {
-        return HttpResponse::Unauthorized().finish();
-    }
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => return ResponseBody::<()>::error(code).into_response(&query),
+    };
 
     let id = match query.get("id") {
         Some(id) => id,
-        None => return HttpResponse::BadRequest().finish(),
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
     };
 
     // search results are pre-cached by search3, so this is usually a cache hit
-    let value = if let Some(song) = data.song_cache().lock().await.get(id) {
-        serde_json::json!({ "song": song })
+    let mut song = if let Some(song) = data.song_cache().get(id).await {
+        song
     } else {
         let track_id = match TrackId::from_id(id) {
             Ok(id) => id,
-            Err(_) => return HttpResponse::BadRequest().finish(),
+            Err(_) => {
+                return ResponseBody::<()>::error_with(
+                    ErrorCode::NotFound,
+                    "Invalid Spotify track ID",
+                )
+                .into_response(&query);
+            }
         };
 
         let track = match data.rspotify().track(track_id, None).await {
             Ok(t) => t,
-            Err(_) => return HttpResponse::InternalServerError().finish(),
+            Err(_) => return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query),
         };
 
-        let song = match Song::from_spotify(&track) {
+        let mut song = match Song::from_spotify(&track) {
             Some(song) => song,
-            None => return HttpResponse::NotFound().finish(),
+            None => return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
         };
 
-        data.song_cache()
-            .lock()
-            .await
-            .insert(song.id.clone(), song.clone());
+        data.cache_songs(std::slice::from_mut(&mut song)).await;
+
+        song
+    };
+    report_transcoding(&data, &user, &query, std::slice::from_mut(&mut song));
+
+    ResponseBody::ok_with(serde_json::json!({ "song": song })).into_response(&query)
+}
+
+pub async fn get_user(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("get_user: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+
+    let username = match query.get("username") {
+        Some(username) => username,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    // users may look themselves up, admins anyone
+    if !user.roles.admin && user.username != *username {
+        log::error!("get_user: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    match data.users().lock().await.get(username) {
+        Some(found) => {
+            ResponseBody::ok_with(serde_json::json!({ "user": UserInfo::from_user(found) }))
+                .into_response(&query)
+        }
+        None => ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    }
+}
 
-        serde_json::json!({ "song": song })
+pub async fn get_users(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("get_users: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
     };
+    if !user.roles.admin {
+        log::error!("get_users: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
 
-    ResponseBody::ok_with(value).into_response()
+    let users: Vec<UserInfo> = data
+        .users()
+        .lock()
+        .await
+        .all()
+        .iter()
+        .map(UserInfo::from_user)
+        .collect();
+
+    ResponseBody::ok_with(serde_json::json!({ "users": { "user": users } })).into_response(&query)
 }
 
 pub async fn ping(
@@ -131,11 +988,11 @@ pub async fn ping(
     data: Data<State>,
     query: Query<HashMap<String, String>>,
 ) -> impl Responder {
-    if !verify(req, &data, &query).await {
+    if let Err(code) = verify(req, &data, &query).await {
         log::error!("ping: Unauthorized.");
-        return HttpResponse::Unauthorized().finish();
+        return ResponseBody::<()>::error(code).into_response(&query);
     }
-    ResponseBody::<()>::ok().into_response()
+    ResponseBody::<()>::ok().into_response(&query)
 }
 
 pub async fn search3(
@@ -143,28 +1000,34 @@ pub async fn search3(
     data: Data<State>,
     query: Query<HashMap<String, String>>,
 ) -> impl Responder {
-    if !verify(req, &data, &query).await {
-        log::error!("search3: Unauthorized.");
-        return HttpResponse::Unauthorized().finish();
-    }
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("search3: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
 
-    let songs = if let Some(search_term) = query.get("query") {
+    let (artists, songs) = if let Some(search_term) = query.get("query") {
         // cap results at 7 to avoid excessive spotify api usage
-        let count = query
-            .get("songCount")
-            .and_then(|n| n.parse().ok())
-            .unwrap_or(4)
-            .min(7);
+        let count = |param: &str| {
+            query
+                .get(param)
+                .and_then(|n| n.parse::<u32>().ok())
+                .unwrap_or(4)
+                .min(7)
+        };
+        let (artist_count, song_count) = (count("artistCount"), count("songCount"));
 
         let results = loop {
             match data
                 .rspotify()
-                .search(
+                .search_multiple(
                     search_term,
-                    SearchType::Track,
+                    [SearchType::Artist, SearchType::Track],
                     None,
                     None,
-                    Some(count),
+                    Some(artist_count.max(song_count).max(1)),
                     None,
                 )
                 .await
@@ -177,27 +1040,55 @@ pub async fn search3(
             }
         };
 
-        if let SearchResult::Tracks(tracks) = results {
-            // collect first, then acquire lock to avoid holding it across the iteration
-            let songs: Vec<Song> = tracks.items.iter().filter_map(Song::from_spotify).collect();
-            let mut cache = data.song_cache().lock().await;
-            for song in &songs {
-                cache.insert(song.id.clone(), song.clone());
+        // collect first, then acquire locks to avoid holding them across the iteration
+        let artists: Vec<Artist> = results
+            .artists
+            .map(|page| page.items)
+            .unwrap_or_default()
+            .iter()
+            .take(artist_count as usize)
+            .map(Artist::from_spotify)
+            .collect();
+        let tracks = results.tracks.map(|page| page.items).unwrap_or_default();
+        let mut songs: Vec<Song> = tracks
+            .iter()
+            .take(song_count as usize)
+            .filter_map(Song::from_spotify)
+            .collect();
+
+        data.cache_songs(&mut songs).await;
+
+        // remember every artist we come across so getArtists has something to list. track
+        // artists lack images, so they don't replace what a full lookup stored
+        let mut seen: Vec<(String, Artist)> =
+            artists.iter().map(|a| (a.id.clone(), a.clone())).collect();
+        for artist in tracks
+            .iter()
+            .flat_map(|t| &t.artists)
+            .filter_map(Artist::from_simplified)
+        {
+            if !seen.iter().any(|(id, _)| *id == artist.id)
+                && data.artist_cache().get(&artist.id).await.is_none()
+            {
+                seen.push((artist.id.clone(), artist));
             }
-            songs
-        } else {
-            vec![]
         }
+        data.artist_cache().insert_many(seen).await;
+
+        report_transcoding(&data, &user, &query, &mut songs);
+
+        (artists, songs)
     } else {
-        vec![]
+        (vec![], vec![])
     };
 
     ResponseBody::ok_with(serde_json::json!({
         "searchResult3": {
+            "artist": artists,
             "song": songs
         }
     }))
-    .into_response()
+    .into_response(&query)
 }
 
 pub async fn stream(
@@ -205,16 +1096,23 @@ pub async fn stream(
     data: Data<State>,
     query: Query<HashMap<String, String>>,
 ) -> impl Responder {
-    if !verify(req, &data, &query).await {
-        log::error!("stream: Unauthorized.");
-        return HttpResponse::Unauthorized().finish();
+    let user = match verify(req.clone(), &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("stream: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.stream {
+        log::error!("stream: {} may not stream.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
     }
 
     let id = match query.get("id") {
         Some(id) => id,
         None => {
             log::error!("stream: Missing 'id'.");
-            return HttpResponse::BadRequest().finish();
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
         }
     };
 
@@ -222,62 +1120,795 @@ pub async fn stream(
         Ok(id) => SpotifyUri::Track { id },
         Err(..) => {
             log::error!("stream: Invalid 'id'.");
-            return HttpResponse::NotFound().finish();
+            return ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query);
         }
     };
 
-    // transcodeOffset (opensubsonic) takes precedence over timeOffset (standard subsonic)
+    // transcodeOffset (opensubsonic) takes precedence over timeOffset (standard subsonic), and
+    // may be fractional
     let time_offset_ms = query
         .get("transcodeOffset")
         .or_else(|| query.get("timeOffset"))
-        .and_then(|t| t.parse::<u32>().ok())
-        .map(|secs| secs * 1000)
+        .and_then(|t| t.parse::<f64>().ok())
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(|secs| (secs * 1000.0).round() as u32)
         .unwrap_or(0);
 
-    let (tx, rx) = unbounded_channel();
+    let mut settings = encoder_settings(&data, &user, &query);
+    let wants_length = query
+        .get("estimateContentLength")
+        .is_some_and(|e| e == "true")
+        || req.headers().contains_key(header::RANGE);
+    // unconstrained vbr can overshoot the size estimate, which would cut off the end of the track
+    if wants_length && matches!(settings.vbr, VbrMode::Vbr) {
+        settings.vbr = VbrMode::Cvbr;
+    }
 
-    // create a fresh player and sink per request to avoid shared state races between streams
     let sess = match data.session().await {
         Ok(sess) => sess,
         Err(e) => {
             log::error!("session: {e}");
-            return HttpResponse::InternalServerError().finish();
+            return ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query);
         }
     };
-    let sink = StreamingSink::new(Default::default(), tx);
-    let player = Player::new(Default::default(), sess, Box::new(NoOpVolume), {
-        let sink = sink.clone();
-        move || Box::new(sink)
-    });
 
-    log::info!("Streaming {} (offset {}ms)...", uri, time_offset_ms);
-    player.load(uri, true, time_offset_ms);
+    if settings.format == OutputFormat::Raw {
+        return match stream_raw(&req, &sess, uri, time_offset_ms, settings).await {
+            Ok(res) => res,
+            Err(body) => body.into_response(&query),
+        };
+    }
+
+    // finished transcodes are served from disk with their exact length
+    let cache_key = settings.cache_key(id);
+    if time_offset_ms == 0
+        && let Some((file, len)) = data.transcode_cache().get(&cache_key).await
+    {
+        log::info!(
+            "Streaming {uri} as {} from cache...",
+            settings.format.suffix()
+        );
+        return match stream_cached(&req, file, len, settings).await {
+            Ok(res) => res,
+            Err(body) => body.into_response(&query),
+        };
+    }
 
-    let stream = async_stream::stream! {
-        // ensure player survives inside this stream
-        let _player = player;
-        let mut rx = rx;
-        let mut pipeline = AudioPipeline::new();
+    // the gain is read even with normalisation off, so songs can report it to clients. it's
+    // cached along with the song, so only the first stream pays for opening another file.
+    let mut song = song_metadata(&data, id).await;
+    if let Some(song) = &mut song {
+        load_replay_gain(&data, &sess, song).await;
+    }
+    let tags = match &song {
+        Some(song) => track_tags(&data, song, settings.format).await,
+        None => TrackTags::default(),
+    };
+    let gain_db = settings
+        .normalisation
+        .gain_db(song.as_ref().and_then(|s| s.replay_gain.as_ref()));
+
+    let mut pipeline = AudioPipeline::new(settings, &cache_key, time_offset_ms as u64, gain_db);
+    let header = pipeline.header_bytes(&tags);
+    let header_len = header.len() as u64;
+
+    // content length and byte ranges are based on an estimate of the whole encoded stream.
+    // durations are rounded up since spotify's milliseconds are truncated when cached.
+    let total = if wants_length {
+        song.as_ref().and_then(|song| {
+            let secs = (song.duration + 1).saturating_sub(time_offset_ms as u64 / 1000);
+            let size = settings.format.estimated_size(settings.bit_rate, secs)?;
+            Some(header_len + size)
+        })
+    } else {
+        None
+    };
+    let range = match total {
+        Some(total) => RangeRequest::from_request(&req, total),
+        None => RangeRequest::Full,
+    };
+    if let (RangeRequest::Unsatisfiable, Some(total)) = (&range, total) {
+        return unsatisfiable_response(total);
+    }
+
+    // a range starting past the headers restarts encoding at the matching time instead, without
+    // headers since the client already has them. the output carries on the stream the client
+    // started on, timestamps and all
+    let (start, _) = range.window(total.unwrap_or(0));
+    let (header, time_offset_ms, skip) = if start > header_len {
+        let audio_pos = start - header_len;
+        let ms = audio_pos * 1000 / settings.format.byte_rate(settings.bit_rate);
+        let skip = audio_pos % settings.format.frame_align();
+        let time_offset_ms = time_offset_ms + pipeline.resume(ms) as u32;
+        (Vec::new(), time_offset_ms, skip)
+    } else {
+        (header, time_offset_ms, start)
+    };
+
+    // a stream of the whole track also fills the cache, unless the client stops early
+    let mut cache_writer = if time_offset_ms == 0 && !header.is_empty() {
+        data.transcode_cache().writer(&cache_key).await
+    } else {
+        None
+    };
+
+    log::info!(
+        "Streaming {} as {} (offset {}ms, {}kbps)...",
+        uri,
+        settings.format.suffix(),
+        time_offset_ms,
+        settings.bit_rate
+    );
+    let mut pcm = match start_playback(sess, uri.clone(), time_offset_ms).await {
+        Ok(pcm) => pcm,
+        Err(body) => return body.into_response(&query),
+    };
 
-        // opus identification and comment headers must precede any audio packets
-        yield Ok::<_, actix_web::Error>(Bytes::from(pipeline.encoder.header_bytes()));
+    let stream = async_stream::stream! {
+        // container headers must precede any audio
+        let header = Bytes::from(header);
+        if !header.is_empty() {
+            if let Some(writer) = &mut cache_writer {
+                writer.write(&header).await;
+            }
+            yield Ok::<_, actix_web::Error>(header.clone());
+        }
 
-        while let Some(pcm_bytes) = rx.recv().await {
+        while let Some(pcm_bytes) = pcm.next().await {
             let samples: &[i16] = bytemuck::cast_slice(&pcm_bytes);
-            let ogg_bytes = pipeline.process(samples);
-            if !ogg_bytes.is_empty() {
-                yield Ok(Bytes::from(ogg_bytes));
+            let encoded = pipeline.process(samples);
+            if !encoded.is_empty() {
+                if let Some(writer) = &mut cache_writer {
+                    writer.write(&encoded).await;
+                }
+                yield Ok(Bytes::from(encoded));
             }
         }
 
-        // flush any samples that didn't fill a complete opus frame
+        // flush any samples that didn't fill a complete frame
         let remaining = pipeline.flush();
+        if let Some(writer) = &mut cache_writer {
+            writer.write(&remaining).await;
+        }
         if !remaining.is_empty() {
             yield Ok(Bytes::from(remaining));
         }
+
+        // a stream cut short by the player would poison the cache, so only keep whole tracks
+        if let Some(mut writer) = cache_writer.take().filter(|_| pcm.is_complete()) {
+            // the cached file has a known length, unlike the stream its header was sent with
+            let mut finished = header.to_vec();
+            pipeline.finish_header(&mut finished, writer.len());
+            if finished != header {
+                writer.rewrite_start(&finished).await;
+            }
+            writer.finish(data.transcode_cache()).await;
+        }
+
+        log::debug!(
+            "Finished {uri}, peak sink queue depth {}/{}.",
+            pcm.peak_queue_depth(),
+            SINK_QUEUE_CAPACITY
+        );
     };
 
-    HttpResponse::Ok()
-        .content_type("audio/ogg; codecs=opus")
-        .streaming(stream)
+    match total {
+        Some(total) => sized_response(
+            settings.format.content_type(),
+            &range,
+            total,
+            skip,
+            Box::pin(stream),
+        ),
+        None => HttpResponse::Ok()
+            .content_type(settings.format.content_type())
+            .streaming(stream),
+    }
+}
+
+pub async fn update_playlist(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let to_add = query_all(&req, "songIdToAdd");
+    let to_remove: Vec<usize> = query_all(&req, "songIndexToRemove")
+        .iter()
+        .filter_map(|i| i.parse().ok())
+        .collect();
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("update_playlist: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.playlist {
+        log::error!("update_playlist: {} may not edit playlists.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let id = match query.get("playlistId") {
+        Some(id) => id,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    if !id.starts_with(PLAYLIST_ID_PREFIX) {
+        return ResponseBody::<()>::error_with(
+            ErrorCode::NotAuthorized,
+            "Spotify playlists are read-only",
+        )
+        .into_response(&query);
+    }
+
+    let mut playlists = data.playlists().lock().await;
+    if playlists.get(id).is_some_and(|p| !p.editable_by(&user)) {
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+    let result = playlists.update(id, |p| {
+        if let Some(name) = query.get("name") {
+            p.name = name.clone();
+        }
+        if let Some(comment) = query.get("comment") {
+            p.comment = Some(comment.clone());
+        }
+        if let Some(public) = query.get("public") {
+            p.public = public == "true";
+        }
+
+        // indices refer to the playlist as it was before any songs are added
+        p.entries = p
+            .entries
+            .drain(..)
+            .enumerate()
+            .filter(|(i, _)| !to_remove.contains(i))
+            .map(|(_, id)| id)
+            .collect();
+        p.entries.extend(to_add);
+    });
+
+    match result {
+        Some(Ok(())) => ResponseBody::<()>::ok().into_response(&query),
+        Some(Err(e)) => {
+            log::error!("update_playlist: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+        None => ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    }
+}
+
+pub async fn update_user(
+    req: HttpRequest,
+    data: Data<State>,
+    query: Query<HashMap<String, String>>,
+) -> impl Responder {
+    let user = match verify(req, &data, &query).await {
+        Ok(user) => user,
+        Err(code) => {
+            log::error!("update_user: Unauthorized.");
+            return ResponseBody::<()>::error(code).into_response(&query);
+        }
+    };
+    if !user.roles.admin {
+        log::error!("update_user: {} may not manage users.", user.username);
+        return ResponseBody::<()>::error(ErrorCode::NotAuthorized).into_response(&query);
+    }
+
+    let username = match query.get("username") {
+        Some(username) => username,
+        None => {
+            return ResponseBody::<()>::error(ErrorCode::MissingParameter).into_response(&query);
+        }
+    };
+
+    let mut users = data.users().lock().await;
+    if users.is_builtin(username) {
+        return ResponseBody::<()>::error_with(
+            ErrorCode::NotAuthorized,
+            "The configured account can only be changed in the config file",
+        )
+        .into_response(&query);
+    }
+
+    let password = match query.get("password").map(|p| decode_password(p)) {
+        Some(None) => {
+            return ResponseBody::<()>::error_with(
+                ErrorCode::Generic,
+                "Invalid hex-encoded password",
+            )
+            .into_response(&query);
+        }
+        password => password.flatten(),
+    };
+
+    // only the parameters that were passed change
+    let result = users.update(username, |u| {
+        if password.is_some() {
+            u.password = password;
+        }
+        if let Some(email) = query.get("email") {
+            u.email = Some(email.clone());
+        }
+        let roles = &mut u.roles;
+        for (key, role) in [
+            ("adminRole", &mut roles.admin),
+            ("streamRole", &mut roles.stream),
+            ("downloadRole", &mut roles.download),
+            ("playlistRole", &mut roles.playlist),
+            ("shareRole", &mut roles.share),
+        ] {
+            if let Some(flag) = query_flag(&query, key) {
+                *role = flag;
+            }
+        }
+    });
+    match result {
+        Some(Ok(())) => ResponseBody::<()>::ok().into_response(&query),
+        Some(Err(e)) => {
+            log::error!("update_user: {e}");
+            ResponseBody::<()>::error(ErrorCode::Generic).into_response(&query)
+        }
+        None => ResponseBody::<()>::error(ErrorCode::NotFound).into_response(&query),
+    }
+}
+
+/// collects every value of a repeated query parameter, e.g. `songId=a&songId=b`.
+fn query_all(req: &HttpRequest, key: &str) -> Vec<String> {
+    Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(|q| {
+            q.into_inner()
+                .into_iter()
+                .filter(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// parses a boolean query parameter, `None` if it's absent or not `true`/`false`.
+fn query_flag(query: &HashMap<String, String>, key: &str) -> Option<bool> {
+    query.get(key).and_then(|v| v.parse().ok())
+}
+
+/// encoder settings for this request: `format` or the configured default for the client, at the
+/// default bitrate for the authenticated user or the client (`c`) capped by `maxBitRate`.
+fn encoder_settings(data: &State, user: &User, query: &HashMap<String, String>) -> EncoderSettings {
+    let format = query
+        .get("format")
+        .and_then(|f| OutputFormat::from_param(f));
+    let max_bit_rate = query.get("maxBitRate").and_then(|b| b.parse().ok());
+    data.transcode().encoder_settings(
+        Some(&user.username),
+        query.get("c").map(String::as_str),
+        format,
+        max_bit_rate,
+    )
+}
+
+/// stamps songs with the format and bitrate `stream` would transcode them to for this client,
+/// rather than whatever was cached with them.
+fn report_transcoding(
+    data: &State,
+    user: &User,
+    query: &HashMap<String, String>,
+    songs: &mut [Song],
+) {
+    let settings = encoder_settings(data, user, query);
+    for song in songs {
+        song.bit_rate = settings.bit_rate;
+        song.transcoded_suffix = settings.format.suffix().into();
+        song.transcoded_content_type = settings.format.content_type().into();
+    }
+}
+
+/// forwards spotify's ogg vorbis file for `uri` without decoding it. its size is known exactly,
+/// so unless it starts at a time offset it is always sent with a length and supports byte ranges.
+async fn stream_raw(
+    req: &HttpRequest,
+    sess: &Session,
+    uri: SpotifyUri,
+    time_offset_ms: u32,
+    settings: EncoderSettings,
+) -> Result<HttpResponse, ResponseBody<()>> {
+    let file = match VorbisFile::open(sess, uri.clone(), settings.bit_rate).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("stream: {uri}: {e}");
+            return Err(ResponseBody::error_with(
+                ErrorCode::NotFound,
+                "Track is unavailable",
+            ));
+        }
+    };
+
+    let total = (time_offset_ms == 0).then(|| file.stream_len());
+    let range = match total {
+        Some(total) => RangeRequest::from_request(req, total),
+        None => RangeRequest::Full,
+    };
+    if let (RangeRequest::Unsatisfiable, Some(total)) = (&range, total) {
+        return Ok(unsatisfiable_response(total));
+    }
+    let (start, _) = range.window(total.unwrap_or(0));
+
+    log::info!(
+        "Streaming {} as raw vorbis (offset {}ms, {}kbps)...",
+        uri,
+        time_offset_ms,
+        file.bit_rate()
+    );
+
+    // the same bounded queue as the sink, so cdn reads are paced by the client
+    let (tx, mut rx) = channel(SINK_QUEUE_CAPACITY);
+    let pump = tokio::task::spawn_blocking(move || file.pump(time_offset_ms, start, tx));
+
+    let stream = async_stream::stream! {
+        while let Some(bytes) = rx.recv().await {
+            yield Ok::<_, actix_web::Error>(Bytes::from(bytes));
+        }
+
+        match pump.await {
+            Ok(Ok(())) => log::debug!("Finished {uri}."),
+            Ok(Err(e)) => log::error!("stream: {uri}: {e}"),
+            Err(e) => log::error!("stream: {uri}: {e}"),
+        }
+    };
+
+    Ok(match total {
+        Some(total) => sized_response(
+            settings.format.content_type(),
+            &range,
+            total,
+            0,
+            Box::pin(stream),
+        ),
+        None => HttpResponse::Ok()
+            .content_type(settings.format.content_type())
+            .streaming(stream),
+    })
+}
+
+/// serves a finished transcode from the cache, honouring byte ranges.
+async fn stream_cached(
+    req: &HttpRequest,
+    mut file: tokio::fs::File,
+    len: u64,
+    settings: EncoderSettings,
+) -> Result<HttpResponse, ResponseBody<()>> {
+    let range = RangeRequest::from_request(req, len);
+    if let RangeRequest::Unsatisfiable = range {
+        return Ok(unsatisfiable_response(len));
+    }
+    let (start, _) = range.window(len);
+    if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+        log::error!("stream: {e}");
+        return Err(ResponseBody::error(ErrorCode::Generic));
+    }
+
+    let stream = async_stream::stream! {
+        loop {
+            let mut buf = vec![0u8; CACHED_READ_SIZE];
+            match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf.truncate(n);
+                    yield Ok::<_, actix_web::Error>(Bytes::from(buf));
+                }
+                Err(e) => {
+                    log::error!("stream: {e}");
+                    break;
+                }
+            }
+        }
+    };
+
+    Ok(sized_response(
+        settings.format.content_type(),
+        &range,
+        len,
+        0,
+        Box::pin(stream),
+    ))
+}
+
+/// fetches cover art for an album id, or an artist id with [`COVER_ARTIST_PREFIX`], scaled to
+/// `size` and cached.
+async fn cover_art(data: &State, id: &str, size: Option<u32>) -> Result<Bytes, ResponseBody<()>> {
+    // each size bucket is cached separately
+    let cache_key = match size {
+        Some(size) => format!("{id}@{size}"),
+        None => id.to_string(),
+    };
+
+    // return cached image to avoid redundant spotify cdn fetches
+    if let Some(bytes) = data.cover_cache().get(&cache_key).await {
+        return Ok(bytes);
+    }
+
+    let images = if let Some(artist_id) = id.strip_prefix(COVER_ARTIST_PREFIX) {
+        let artist_id = match ArtistId::from_id(artist_id) {
+            Ok(id) => id,
+            Err(_) => {
+                return Err(ResponseBody::error_with(
+                    ErrorCode::NotFound,
+                    "Invalid Spotify artist ID",
+                ));
+            }
+        };
+
+        match data.rspotify().artist(artist_id).await {
+            Ok(a) => a.images,
+            Err(_) => return Err(ResponseBody::error(ErrorCode::Generic)),
+        }
+    } else {
+        let album_id = match AlbumId::from_id(id) {
+            Ok(id) => id,
+            Err(_) => {
+                return Err(ResponseBody::error_with(
+                    ErrorCode::NotFound,
+                    "Invalid Spotify album ID",
+                ));
+            }
+        };
+
+        match data.rspotify().album(album_id, None).await {
+            Ok(t) => t.images,
+            Err(_) => return Err(ResponseBody::error(ErrorCode::Generic)),
+        }
+    };
+
+    let source = match CoverSource::pick(&images, size) {
+        Some(source) => source,
+        None => return Err(ResponseBody::error(ErrorCode::NotFound)),
+    };
+
+    let bytes = match data.http().get(source.url()).send().await {
+        Ok(resp) => match resp.bytes().await {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(ResponseBody::error(ErrorCode::Generic)),
+        },
+        Err(_) => return Err(ResponseBody::error(ErrorCode::Generic)),
+    };
+
+    let bytes = if let CoverSource::Resize(_, size) = source {
+        // decoding and resampling is cpu-bound, keep it off the async workers
+        match tokio::task::spawn_blocking(move || resize_cover(&bytes, size)).await {
+            Ok(Ok(bytes)) => bytes,
+            Ok(Err(e)) => {
+                log::error!("cover_art: resize: {e}");
+                return Err(ResponseBody::error(ErrorCode::Generic));
+            }
+            Err(e) => {
+                log::error!("cover_art: resize: {e}");
+                return Err(ResponseBody::error(ErrorCode::Generic));
+            }
+        }
+    } else {
+        bytes
+    };
+    data.cover_cache().insert(cache_key, bytes.clone()).await;
+
+    let stats = data.cover_cache().stats();
+    log::debug!(
+        "cover cache: {} hits, {} misses, {} entries, {} bytes",
+        stats.hits,
+        stats.misses,
+        stats.entries,
+        stats.bytes
+    );
+    Ok(bytes)
+}
+
+/// loads `uri` into a fresh player, failing with the error to send if it can't be played.
+async fn start_playback(
+    sess: Session,
+    uri: SpotifyUri,
+    time_offset_ms: u32,
+) -> Result<PcmStream, ResponseBody<()>> {
+    match PcmStream::start(sess, uri.clone(), time_offset_ms).await {
+        Ok(pcm) => Ok(pcm),
+        Err(LoadError::Unavailable) => {
+            log::error!("{uri} is unavailable.");
+            Err(ResponseBody::error_with(
+                ErrorCode::NotFound,
+                "Track is unavailable",
+            ))
+        }
+        Err(LoadError::TimedOut) => {
+            log::error!("{uri} timed out while loading.");
+            Err(ResponseBody::error_with(
+                ErrorCode::Generic,
+                "Track failed to load",
+            ))
+        }
+    }
+}
+
+/// transcodes the whole of `uri` into the transcode cache, returning the finished file and its
+/// length. the file is returned even if the cache doesn't keep it, e.g. when it's too large.
+async fn transcode(
+    data: &State,
+    sess: Session,
+    uri: SpotifyUri,
+    settings: EncoderSettings,
+    tags: &TrackTags,
+    gain_db: f64,
+    cache_key: &str,
+) -> Result<(tokio::fs::File, u64), ResponseBody<()>> {
+    let failed = || ResponseBody::error_with(ErrorCode::Generic, "Track failed to transcode");
+    let Some(mut writer) = data.transcode_cache().writer(cache_key).await else {
+        return Err(failed());
+    };
+    let mut pcm = start_playback(sess, uri.clone(), 0).await?;
+
+    let mut pipeline = AudioPipeline::new(settings, cache_key, 0, gain_db);
+    let header = pipeline.header_bytes(tags);
+    writer.write(&header).await;
+    while let Some(pcm_bytes) = pcm.next().await {
+        writer
+            .write(&pipeline.process(bytemuck::cast_slice(&pcm_bytes)))
+            .await;
+    }
+    writer.write(&pipeline.flush()).await;
+
+    if !pcm.is_complete() {
+        log::error!("download: {uri} stopped before the end.");
+        return Err(failed());
+    }
+
+    let mut finished = header.clone();
+    pipeline.finish_header(&mut finished, writer.len());
+    if finished != header {
+        writer.rewrite_start(&finished).await;
+    }
+    // an open file stays readable after the writer deletes it for not being cached
+    let file = writer.reopen().await.ok_or_else(failed)?;
+    let len = writer.len();
+    writer.finish(data.transcode_cache()).await;
+    Ok((file, len))
+}
+
+/// an attachment named `Artist - Title.ext`, with an ascii fallback for old clients.
+fn download_disposition(song: Option<&Song>, id: &str, format: OutputFormat) -> ContentDisposition {
+    let stem = match song {
+        Some(Song {
+            artist: Some(artist),
+            title,
+            ..
+        }) => format!("{artist} - {title}"),
+        Some(song) => song.title.clone(),
+        None => id.to_string(),
+    };
+    // keep the name to a single path component
+    let name: String = format!("{stem}.{}", format.suffix())
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let ascii: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![
+            DispositionParam::Filename(ascii),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_string()),
+                language_tag: None,
+                value: name.into_bytes(),
+            }),
+        ],
+    }
+}
+
+/// a single song's metadata, through the song cache.
+async fn song_metadata(data: &State, id: &str) -> Option<Song> {
+    match data.songs(&[id.to_string()]).await {
+        Ok(songs) => songs.into_iter().next(),
+        Err(e) => {
+            log::error!("song_metadata: {e}");
+            None
+        }
+    }
+}
+
+/// reads spotify's normalisation data for `song` unless it is already known, remembering it in
+/// the song cache. failures leave the song without it.
+async fn load_replay_gain(data: &State, sess: &Session, song: &mut Song) {
+    if song.replay_gain.is_some() {
+        return;
+    }
+    let Ok(id) = SpotifyId::from_base62(&song.id) else {
+        return;
+    };
+
+    // every encode carries the same data, so open the smallest
+    let file = match VorbisFile::open(sess, SpotifyUri::Track { id }, 0).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::warn!("load_replay_gain: {}: {e}", song.id);
+            return;
+        }
+    };
+    match tokio::task::spawn_blocking(move || file.replay_gain()).await {
+        Ok(Ok(replay_gain)) => {
+            song.replay_gain = Some(replay_gain);
+            data.song_cache()
+                .insert(song.id.clone(), song.clone())
+                .await;
+        }
+        Ok(Err(e)) => log::warn!("load_replay_gain: {}: {e}", song.id),
+        Err(e) => log::warn!("load_replay_gain: {}: {e}", song.id),
+    }
+}
+
+/// tags for `song`, with its album cover if `format` embeds tags at all. a missing cover
+/// doesn't fail the stream.
+async fn track_tags(data: &State, song: &Song, format: OutputFormat) -> TrackTags {
+    let cover = match (&song.cover_art, format.has_tags()) {
+        (Some(id), true) => match cover_art(data, id, Some(EMBEDDED_COVER_SIZE)).await {
+            Ok(bytes) => Some(bytes),
+            Err(_) => {
+                log::warn!("track_tags: no cover for {}", song.id);
+                None
+            }
+        },
+        _ => None,
+    };
+    TrackTags::new(song, cover)
+}
+
+/// fetches a spotify playlist, caching its songs and its listing. without `all`, only the first
+/// page is read.
+async fn fetch_spotify_playlist(
+    data: &Data<State>,
+    id: &str,
+    all: bool,
+) -> Result<(FullPlaylist, Vec<Song>)> {
+    let playlist_id = PlaylistId::from_id(id)?;
+    let playlist = data
+        .rspotify()
+        .playlist(playlist_id.as_ref(), None, None)
+        .await?;
+
+    let mut items = playlist.tracks.items.clone();
+    while all && (items.len() as u32) < playlist.tracks.total {
+        let page = data
+            .rspotify()
+            .playlist_items_manual(
+                playlist_id.as_ref(),
+                None,
+                None,
+                Some(100),
+                Some(items.len() as u32),
+            )
+            .await?;
+        if page.items.is_empty() {
+            break;
+        }
+        items.extend(page.items);
+    }
+
+    // episodes and local files can't be streamed through librespot's track path
+    let mut songs: Vec<Song> = items
+        .iter()
+        .filter_map(|item| match &item.track {
+            Some(PlayableItem::Track(t)) if !item.is_local => Song::from_spotify(t),
+            _ => None,
+        })
+        .collect();
+
+    data.cache_songs(&mut songs).await;
+    data.playlist_cache()
+        .insert(id.to_string(), Playlist::from_spotify(&playlist, &songs))
+        .await;
+
+    Ok((playlist, songs))
 }
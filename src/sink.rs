@@ -1,14 +1,37 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use librespot::playback::player::PlayerEventChannel;
+use tokio::sync::mpsc::Receiver;
+
 use crate::prelude::*;
 
+// decoded packets allowed to queue between librespot and the encoder before decoding blocks
+pub const SINK_QUEUE_CAPACITY: usize = 16;
+
 #[derive(Clone, Debug)]
 pub struct StreamingSink {
     format: AudioFormat,
-    tx: UnboundedSender<Vec<u8>>,
+    tx: Sender<Vec<u8>>,
+    // deepest the queue has been, shared across the player's clone of the sink
+    peak_depth: Arc<AtomicUsize>,
 }
 
 impl StreamingSink {
-    pub fn new(format: AudioFormat, tx: UnboundedSender<Vec<u8>>) -> Self {
-        Self { format, tx }
+    pub fn new(format: AudioFormat, tx: Sender<Vec<u8>>) -> Self {
+        Self {
+            format,
+            tx,
+            peak_depth: Default::default(),
+        }
+    }
+
+    /// number of decoded packets waiting for the encoder.
+    pub fn queue_depth(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    pub fn peak_queue_depth(&self) -> usize {
+        self.peak_depth.load(Ordering::Relaxed)
     }
 }
 
@@ -25,7 +48,155 @@ impl Sink for StreamingSink {
             },
             AudioPacket::Raw(bytes) => bytes,
         };
-        _ = self.tx.send(bytes);
+        // block the player thread until the encoder catches up, so decoding is paced by the
+        // http consumer. librespot drives sinks from inside its own runtime, where tokio's
+        // `blocking_send` refuses to run, hence the standalone executor.
+        //
+        // the receiver is dropped once the http response goes away. failing the write makes
+        // librespot pause instead of decoding the rest of the track into the void.
+        futures_executor::block_on(self.tx.send(bytes))
+            .map_err(|_| SinkError::OnWrite("stream receiver dropped".to_string()))?;
+
+        self.peak_depth
+            .fetch_max(self.queue_depth(), Ordering::Relaxed);
         Ok(())
     }
 }
+
+/// owns a per-request player for the lifetime of an http response stream. dropping it (e.g. when
+/// the client disconnects or skips) stops playback and tears the player down off the async
+/// runtime, since librespot's destructor blocks joining its decoder thread.
+pub struct PlayerGuard {
+    player: Option<Arc<Player>>,
+    uri: SpotifyUri,
+}
+
+impl PlayerGuard {
+    pub const fn new(player: Arc<Player>, uri: SpotifyUri) -> Self {
+        Self {
+            player: Some(player),
+            uri,
+        }
+    }
+}
+
+impl std::ops::Deref for PlayerGuard {
+    type Target = Player;
+
+    fn deref(&self) -> &Player {
+        self.player.as_ref().expect("player is only taken on drop")
+    }
+}
+
+impl Drop for PlayerGuard {
+    fn drop(&mut self) {
+        if let Some(player) = self.player.take() {
+            log::info!("Stopping player for {}.", self.uri);
+            player.stop();
+            std::thread::spawn(move || drop(player));
+        }
+    }
+}
+
+/// why a track couldn't be played.
+#[derive(Clone, Copy, Debug)]
+pub enum LoadError {
+    Unavailable,
+    TimedOut,
+}
+
+/// decoded s16 stereo pcm of a single track, played by a fresh player and sink per request to
+/// avoid shared state races between streams.
+pub struct PcmStream {
+    // held so playback stops when the stream is dropped
+    _player: PlayerGuard,
+    events: PlayerEventChannel,
+    rx: Receiver<Vec<u8>>,
+    sink: StreamingSink,
+    // set once the player has stopped, after which only queued pcm is left
+    stopped: bool,
+    // whether the player stopped at the end of the track rather than being cut short
+    complete: bool,
+}
+
+impl PcmStream {
+    /// loads `uri` from `offset_ms` onwards and waits until playback actually starts, so
+    /// unplayable tracks fail before any audio is sent.
+    pub async fn start(sess: Session, uri: SpotifyUri, offset_ms: u32) -> Result<Self, LoadError> {
+        let (tx, rx) = channel(SINK_QUEUE_CAPACITY);
+        let sink = StreamingSink::new(Default::default(), tx);
+        let player = Player::new(Default::default(), sess, Box::new(NoOpVolume), {
+            let sink = sink.clone();
+            move || Box::new(sink)
+        });
+        // stops the player on every exit path, including the client disconnecting mid-stream
+        let player = PlayerGuard::new(player, uri.clone());
+
+        // subscribe before loading so no event can be missed
+        let mut events = player.get_player_event_channel();
+        player.load(uri, true, offset_ms);
+
+        let started = tokio::time::timeout(STREAM_LOAD_TIMEOUT, async {
+            while let Some(event) = events.recv().await {
+                match event {
+                    PlayerEvent::Playing { .. } => return true,
+                    PlayerEvent::Unavailable { .. }
+                    | PlayerEvent::EndOfTrack { .. }
+                    | PlayerEvent::Stopped { .. } => return false,
+                    _ => (),
+                }
+            }
+            false
+        })
+        .await;
+
+        match started {
+            Ok(true) => Ok(Self {
+                _player: player,
+                events,
+                rx,
+                sink,
+                stopped: false,
+                complete: false,
+            }),
+            Ok(false) => Err(LoadError::Unavailable),
+            Err(_) => Err(LoadError::TimedOut),
+        }
+    }
+
+    /// the next packet of pcm bytes, or `None` once the player has stopped and everything it
+    /// decoded has been handed out.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        // the player keeps a clone of the sink, so the pcm channel never closes on its own. the
+        // sink is written synchronously before the player emits its end event, so once that
+        // arrives everything left is already queued.
+        while !self.stopped {
+            tokio::select! {
+                biased;
+                Some(pcm_bytes) = self.rx.recv() => return Some(pcm_bytes),
+                event = self.events.recv() => match event {
+                    Some(PlayerEvent::EndOfTrack { .. }) => {
+                        self.stopped = true;
+                        self.complete = true;
+                    }
+                    Some(PlayerEvent::Stopped { .. } | PlayerEvent::Unavailable { .. }) | None => {
+                        self.stopped = true;
+                    }
+                    Some(_) => (),
+                },
+            }
+        }
+
+        // drain anything decoded between the last packet and the end event
+        self.rx.try_recv().ok()
+    }
+
+    /// whether the whole track was played, rather than playback stopping early.
+    pub const fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn peak_queue_depth(&self) -> usize {
+        self.sink.peak_queue_depth()
+    }
+}
@@ -2,7 +2,6 @@ use crate::auth::*;
 use crate::prelude::*;
 
 pub struct LibreSpotify {
-    cred: Credentials,
     sess: Mutex<Session>,
 }
 
@@ -28,16 +27,26 @@ impl LibreSpotify {
 }
 
 pub struct State {
-    rspot: RSpotify,                            // spotify dev api
-    lspot: LibreSpotify,                        // librespot config
-    http: HttpClient,                           // reqwests client
-    song_cache: Mutex<HashMap<String, Song>>,   // song metadata cache
-    cover_cache: Mutex<HashMap<String, Bytes>>, // cover-art metadata cache
-    rate_limits: Mutex<HashMap<IpAddr, RateLimit>>,
+    rspot: RSpotify,                     // spotify dev api
+    lspot: LibreSpotify,                 // librespot config
+    http: HttpClient,                    // reqwests client
+    song_cache: DiskCache<Song>,         // song metadata cache
+    artist_cache: DiskCache<Artist>,     // artists seen via search/browse
+    playlist_cache: DiskCache<Playlist>, // spotify playlist imports as listed
+    cover_cache: TieredCache<Bytes>,     // cover-art cache
+    transcode_cache: TranscodeCache,     // finished transcodes
+    playlists: Mutex<PlaylistStore>,     // server-side playlists
+    users: Mutex<UserStore>,             // subsonic accounts
+    spotify_playlists: Vec<String>,      // read-only spotify playlist imports
+    transcode: TranscodeConfig,          // output format and encoder defaults
+    rate_limiter: Mutex<RateLimiter>,    // failed login tracking
+    used_salts: Mutex<SaltCache>,        // token salts seen recently
+    proxy: ProxyConfig,                  // trusted reverse proxies
 }
 
 impl State {
-    pub async fn new(cred: Credentials) -> Result<Self> {
+    pub async fn new(cfg: &Config) -> Result<Self> {
+        let cred = cfg.cred();
         let rspot_cred =
             rspotify::Credentials::new(cred.dev().client_id(), cred.dev().client_secret());
         let rspot = RSpotify::new(rspot_cred);
@@ -45,15 +54,54 @@ impl State {
 
         let sess = Mutex::new(create_session().await?);
 
-        let lspot = LibreSpotify { cred, sess };
+        let lspot = LibreSpotify { sess };
+
+        let cache = cfg.cache();
+        let db = open_database(cache.path())?;
+        let song_cache = DiskCache::open(
+            db.clone(),
+            "songs",
+            cache.song_ttl(),
+            cache.song_max_bytes(),
+        )?;
+        let artist_cache = DiskCache::open(
+            db.clone(),
+            "artists",
+            cache.artist_ttl(),
+            cache.artist_max_bytes(),
+        )?;
+        let playlist_cache = DiskCache::open(
+            db.clone(),
+            "playlists",
+            cache.playlist_ttl(),
+            cache.playlist_max_bytes(),
+        )?;
+        let cover_cache = TieredCache::new(
+            MemoryCache::new(cache.cover_memory_bytes()),
+            DiskCache::open(db, "covers", cache.cover_ttl(), cache.cover_max_bytes())?,
+        );
+        let transcode_cache =
+            TranscodeCache::open(cache.transcode_dir(), cache.transcode_max_bytes())?;
 
         let app_state = Self {
             rspot,
             lspot,
             http: Default::default(),
-            song_cache: Default::default(),
-            cover_cache: Default::default(),
-            rate_limits: Default::default(),
+            song_cache,
+            artist_cache,
+            playlist_cache,
+            cover_cache,
+            transcode_cache,
+            playlists: Mutex::new(PlaylistStore::load(PLAYLISTS_PATH)?),
+            users: Mutex::new(UserStore::load(USERS_PATH, cred.account())?),
+            spotify_playlists: cfg.spotify_playlists().to_vec(),
+            transcode: cfg.transcode().clone(),
+            rate_limiter: Mutex::new(RateLimiter::new(cfg.rate_limit().clone())),
+            used_salts: Mutex::new(SaltCache::new(
+                cfg.auth().salt_window(),
+                cfg.auth().max_salts(),
+            )),
+            proxy: cfg.proxy().clone(),
         };
         Ok(app_state)
     }
@@ -66,23 +114,105 @@ impl State {
         &self.http
     }
 
-    pub const fn cred(&self) -> &Credentials {
-        &self.lspot.cred
-    }
-
     pub async fn session(&self) -> Result<Session> {
         self.lspot.session().await
     }
 
-    pub const fn song_cache(&self) -> &Mutex<HashMap<String, Song>> {
+    pub const fn song_cache(&self) -> &DiskCache<Song> {
         &self.song_cache
     }
 
-    pub const fn cover_cache(&self) -> &Mutex<HashMap<String, Bytes>> {
+    pub const fn artist_cache(&self) -> &DiskCache<Artist> {
+        &self.artist_cache
+    }
+
+    pub const fn playlist_cache(&self) -> &DiskCache<Playlist> {
+        &self.playlist_cache
+    }
+
+    pub const fn cover_cache(&self) -> &TieredCache<Bytes> {
         &self.cover_cache
     }
 
-    pub const fn rate_limits(&self) -> &Mutex<HashMap<IpAddr, RateLimit>> {
-        &self.rate_limits
+    pub const fn transcode_cache(&self) -> &TranscodeCache {
+        &self.transcode_cache
+    }
+
+    pub const fn playlists(&self) -> &Mutex<PlaylistStore> {
+        &self.playlists
+    }
+
+    pub const fn users(&self) -> &Mutex<UserStore> {
+        &self.users
+    }
+
+    pub const fn transcode(&self) -> &TranscodeConfig {
+        &self.transcode
+    }
+
+    pub fn spotify_playlists(&self) -> &[String] {
+        &self.spotify_playlists
+    }
+
+    /// caches freshly fetched songs, keeping what was learned about them since they were last
+    /// fetched: normalisation data, and isrcs that album listings lack.
+    pub async fn cache_songs(&self, songs: &mut [Song]) {
+        for song in songs.iter_mut() {
+            if let Some(cached) = self.song_cache.get(&song.id).await {
+                song.replay_gain = song.replay_gain.or(cached.replay_gain);
+                if song.isrc.is_empty() {
+                    song.isrc = cached.isrc;
+                }
+            }
+        }
+        self.song_cache
+            .insert_many(songs.iter().map(|s| (s.id.clone(), s.clone())).collect())
+            .await;
+    }
+
+    /// resolves track ids to songs in order, fetching any cache misses from spotify.
+    pub async fn songs(&self, ids: &[String]) -> Result<Vec<Song>> {
+        let mut found = HashMap::new();
+        for id in ids {
+            if !found.contains_key(id)
+                && let Some(song) = self.song_cache.get(id).await
+            {
+                found.insert(id.clone(), song);
+            }
+        }
+
+        let mut missing: Vec<&String> = ids.iter().filter(|id| !found.contains_key(*id)).collect();
+        missing.sort();
+        missing.dedup();
+
+        // the several-tracks endpoint accepts at most 50 ids per request
+        for chunk in missing.chunks(50) {
+            let track_ids = chunk
+                .iter()
+                .filter_map(|id| TrackId::from_id(id.as_str()).ok());
+            let tracks = self.rspot.tracks(track_ids, None).await?;
+
+            let songs: Vec<(String, Song)> = tracks
+                .iter()
+                .filter_map(Song::from_spotify)
+                .map(|song| (song.id.clone(), song))
+                .collect();
+            found.extend(songs.iter().cloned());
+            self.song_cache.insert_many(songs).await;
+        }
+
+        Ok(ids.iter().filter_map(|id| found.get(id).cloned()).collect())
+    }
+
+    pub const fn rate_limiter(&self) -> &Mutex<RateLimiter> {
+        &self.rate_limiter
+    }
+
+    pub const fn used_salts(&self) -> &Mutex<SaltCache> {
+        &self.used_salts
+    }
+
+    pub const fn proxy(&self) -> &ProxyConfig {
+        &self.proxy
     }
 }
@@ -0,0 +1,716 @@
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::prelude::*;
+
+// eviction trims the table to this fraction of its budget so it doesn't run on every insert
+const EVICT_TARGET_PERCENT: u64 = 90;
+
+// extension of transcodes still being written
+const TRANSCODE_PART_SUFFIX: &str = "part";
+
+/// key-value cache for spotify metadata and cover art. lookups and writes are best-effort:
+/// backend failures are logged and surface as cache misses.
+pub trait MetadataCache<V>: Send + Sync {
+    fn get(&self, key: &str) -> impl Future<Output = Option<V>> + Send;
+
+    fn insert(&self, key: String, value: V) -> impl Future<Output = ()> + Send {
+        self.insert_many(vec![(key, value)])
+    }
+
+    fn insert_many(&self, entries: Vec<(String, V)>) -> impl Future<Output = ()> + Send;
+}
+
+/// values that can be persisted by a [`DiskCache`].
+pub trait Codec: Sized + Send + 'static {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Codec for Bytes {
+    fn encode(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(Bytes::copy_from_slice(bytes))
+    }
+}
+
+impl Codec for Artist {
+    fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+impl Codec for Playlist {
+    fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+impl Codec for Song {
+    fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+/// values that can be held in a byte-budgeted [`MemoryCache`].
+pub trait Weigh {
+    fn weight(&self) -> usize;
+}
+
+impl Weigh for Bytes {
+    fn weight(&self) -> usize {
+        self.len()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// in-memory lru cache bounded by the total weight of its values. the lock is synchronous and
+/// never held across an await, so lookups can't stall behind slow i/o in another request.
+pub struct MemoryCache<V> {
+    lru: parking_lot::Mutex<Lru<V>>,
+    max_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Lru<V> {
+    entries: HashMap<String, (V, u64)>,
+    // recency order: tick → key, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl<V: Weigh + Clone> MemoryCache<V> {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            lru: parking_lot::Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                bytes: 0,
+            }),
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        let mut lru = self.lru.lock();
+        lru.tick += 1;
+        let tick = lru.tick;
+
+        let Some((value, last)) = lru.entries.get_mut(key) else {
+            drop(lru);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let (value, last) = (value.clone(), std::mem::replace(last, tick));
+        lru.order.remove(&last);
+        lru.order.insert(tick, key.to_string());
+        drop(lru);
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    pub fn insert(&self, key: String, value: V) {
+        let weight = key.len() + value.weight();
+        // a single value larger than the whole budget would just flush everything else
+        if weight > self.max_bytes {
+            return;
+        }
+
+        let mut lru = self.lru.lock();
+        lru.tick += 1;
+        let tick = lru.tick;
+
+        if let Some((old, last)) = lru.entries.insert(key.clone(), (value, tick)) {
+            lru.bytes -= key.len() + old.weight();
+            lru.order.remove(&last);
+        }
+        lru.order.insert(tick, key);
+        lru.bytes += weight;
+
+        let mut evicted = 0;
+        while lru.bytes > self.max_bytes {
+            let Some((_, key)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some((old, _)) = lru.entries.remove(&key) {
+                lru.bytes -= key.len() + old.weight();
+                evicted += 1;
+            }
+        }
+        drop(lru);
+
+        if evicted > 0 {
+            log::debug!("memory cache: evicted {evicted} entries");
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len(),
+            bytes: lru.bytes,
+        }
+    }
+}
+
+/// a [`MemoryCache`] in front of a [`DiskCache`]. disk hits are promoted into memory.
+pub struct TieredCache<V> {
+    memory: MemoryCache<V>,
+    disk: DiskCache<V>,
+}
+
+impl<V: Codec + Weigh + Clone + Sync> TieredCache<V> {
+    pub const fn new(memory: MemoryCache<V>, disk: DiskCache<V>) -> Self {
+        Self { memory, disk }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.memory.stats()
+    }
+}
+
+impl<V: Codec + Weigh + Clone + Sync> MetadataCache<V> for TieredCache<V> {
+    async fn get(&self, key: &str) -> Option<V> {
+        if let Some(value) = self.memory.get(key) {
+            return Some(value);
+        }
+        let value = self.disk.get(key).await?;
+        self.memory.insert(key.to_string(), value.clone());
+        Some(value)
+    }
+
+    async fn insert_many(&self, entries: Vec<(String, V)>) {
+        for (key, value) in &entries {
+            self.memory.insert(key.clone(), value.clone());
+        }
+        self.disk.insert_many(entries).await
+    }
+}
+
+/// opens (or creates) the database file shared by every [`DiskCache`] table.
+pub fn open_database(path: &Path) -> Result<Arc<Database>> {
+    Ok(Arc::new(Database::create(path)?))
+}
+
+/// a single table in the on-disk cache, storing `(stored_at, payload)` per key. entries older
+/// than `ttl` are treated as misses, and the oldest entries are evicted once the table's payload
+/// exceeds `max_bytes`.
+pub struct DiskCache<V> {
+    table: Arc<Table>,
+    _value: PhantomData<fn() -> V>,
+}
+
+struct Table {
+    db: Arc<Database>,
+    def: TableDefinition<'static, &'static str, (u64, &'static [u8])>,
+    ttl: Duration,
+    max_bytes: u64,
+    size: AtomicU64,
+}
+
+impl<V: Codec> DiskCache<V> {
+    pub fn open(
+        db: Arc<Database>,
+        name: &'static str,
+        ttl: Duration,
+        max_bytes: u64,
+    ) -> Result<Self> {
+        let table = Table {
+            db,
+            def: TableDefinition::new(name),
+            ttl,
+            max_bytes,
+            size: AtomicU64::new(0),
+        };
+
+        // drop anything that expired while we were offline and learn the current size
+        table.evict(table.max_bytes)?;
+        log::info!(
+            "cache '{name}': {} bytes on disk",
+            table.size.load(Ordering::Relaxed)
+        );
+
+        Ok(Self {
+            table: Arc::new(table),
+            _value: PhantomData,
+        })
+    }
+
+    /// every unexpired value, in no particular order.
+    pub async fn values(&self) -> Vec<V> {
+        let table = self.table.clone();
+        let result = tokio::task::spawn_blocking(move || table.values()).await;
+        match result {
+            Ok(Ok(values)) => values.iter().filter_map(|b| V::decode(b)).collect(),
+            Ok(Err(e)) => {
+                log::error!("cache values: {e}");
+                Vec::new()
+            }
+            Err(e) => {
+                log::error!("cache values: {e}");
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl<V: Codec> MetadataCache<V> for DiskCache<V> {
+    async fn get(&self, key: &str) -> Option<V> {
+        let table = self.table.clone();
+        let key = key.to_string();
+
+        // redb does blocking file i/o, keep it off the async workers
+        let result = tokio::task::spawn_blocking(move || table.get(&key)).await;
+        match result {
+            Ok(Ok(bytes)) => bytes.and_then(|b| V::decode(&b)),
+            Ok(Err(e)) => {
+                log::error!("cache get: {e}");
+                None
+            }
+            Err(e) => {
+                log::error!("cache get: {e}");
+                None
+            }
+        }
+    }
+
+    async fn insert_many(&self, entries: Vec<(String, V)>) {
+        if entries.is_empty() {
+            return;
+        }
+        let table = self.table.clone();
+        let entries: Vec<(String, Vec<u8>)> =
+            entries.into_iter().map(|(k, v)| (k, v.encode())).collect();
+
+        let result = tokio::task::spawn_blocking(move || table.insert(entries)).await;
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => log::error!("cache insert: {e}"),
+            Err(e) => log::error!("cache insert: {e}"),
+        }
+    }
+}
+
+impl Table {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(self.def) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(table.get(key)?.and_then(|entry| {
+            let (stored_at, payload) = entry.value();
+            (!self.expired(stored_at, now_secs())).then(|| payload.to_vec())
+        }))
+    }
+
+    fn values(&self) -> Result<Vec<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(self.def) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = now_secs();
+        let mut values = Vec::new();
+        for entry in table.iter()? {
+            let (_, value) = entry?;
+            let (stored_at, payload) = value.value();
+            if !self.expired(stored_at, now) {
+                values.push(payload.to_vec());
+            }
+        }
+        Ok(values)
+    }
+
+    fn insert(&self, entries: Vec<(String, Vec<u8>)>) -> Result<()> {
+        let now = now_secs();
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(self.def)?;
+            for (key, payload) in &entries {
+                let replaced = table
+                    .insert(key.as_str(), (now, payload.as_slice()))?
+                    .map(|old| old.value().1.len() as u64)
+                    .unwrap_or(0);
+                self.size.fetch_add(payload.len() as u64, Ordering::Relaxed);
+                self.size.fetch_sub(replaced, Ordering::Relaxed);
+            }
+        }
+        txn.commit()?;
+
+        if self.size.load(Ordering::Relaxed) > self.max_bytes {
+            self.evict(self.max_bytes * EVICT_TARGET_PERCENT / 100)?;
+        }
+        Ok(())
+    }
+
+    /// removes expired entries, then the oldest ones until the table fits in `target` bytes.
+    fn evict(&self, target: u64) -> Result<()> {
+        let now = now_secs();
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(self.def)?;
+
+            let mut entries = Vec::new();
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                let (stored_at, payload) = value.value();
+                entries.push((stored_at, key.value().to_string(), payload.len() as u64));
+            }
+
+            let mut size: u64 = entries.iter().map(|(_, _, len)| len).sum();
+            entries.sort_unstable();
+
+            let mut evicted = 0;
+            for (stored_at, key, len) in entries {
+                if !self.expired(stored_at, now) && size <= target {
+                    break;
+                }
+                table.remove(key.as_str())?;
+                size -= len;
+                evicted += 1;
+            }
+            if evicted > 0 {
+                log::info!("cache '{}': evicted {evicted} entries", self.def);
+            }
+            self.size.store(size, Ordering::Relaxed);
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn expired(&self, stored_at: u64, now: u64) -> bool {
+        now.saturating_sub(stored_at) > self.ttl.as_secs()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// finished transcodes kept as plain files named by their cache key, so they can be served with
+/// byte ranges straight from disk. the least recently used files are deleted once the directory
+/// exceeds `max_bytes`.
+pub struct TranscodeCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    files: parking_lot::Mutex<TranscodeIndex>,
+}
+
+struct TranscodeIndex {
+    // key → (size, last used)
+    entries: HashMap<String, (u64, u64)>,
+    bytes: u64,
+}
+
+impl TranscodeCache {
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut index = TranscodeIndex {
+            entries: HashMap::new(),
+            bytes: 0,
+        };
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // leftovers from transcodes interrupted by a shutdown
+            if path
+                .extension()
+                .is_some_and(|ext| ext == TRANSCODE_PART_SUFFIX)
+            {
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let meta = entry.metadata()?;
+            let Some(key) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !meta.is_file() {
+                continue;
+            }
+            let used = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            index.bytes += meta.len();
+            index.entries.insert(key, (meta.len(), used));
+        }
+
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            files: parking_lot::Mutex::new(index),
+        };
+        for path in cache.evict(max_bytes) {
+            let _ = std::fs::remove_file(path);
+        }
+        log::info!(
+            "transcode cache: {} bytes on disk",
+            cache.files.lock().bytes
+        );
+        Ok(cache)
+    }
+
+    /// opens the cached transcode for `key`, returning it with its length.
+    pub async fn get(&self, key: &str) -> Option<(tokio::fs::File, u64)> {
+        let len = {
+            let mut files = self.files.lock();
+            let (len, used) = files.entries.get_mut(key)?;
+            *used = now_secs();
+            *len
+        };
+
+        let path = self.dir.join(key);
+        let result = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(path)?;
+            // keeps recency across restarts, where the index is rebuilt from mtimes
+            let _ = file.set_modified(SystemTime::now());
+            Ok::<_, std::io::Error>(file)
+        })
+        .await;
+        match result {
+            Ok(Ok(file)) => Some((tokio::fs::File::from_std(file), len)),
+            Ok(Err(e)) => {
+                log::warn!("transcode cache: {key}: {e}");
+                self.remove(key);
+                None
+            }
+            Err(e) => {
+                log::error!("transcode cache: {key}: {e}");
+                None
+            }
+        }
+    }
+
+    /// starts a new transcode for `key`, which only becomes visible once it is finished.
+    pub async fn writer(&self, key: &str) -> Option<TranscodeWriter> {
+        let tmp = self.dir.join(format!(
+            "{key}.{:08x}.{TRANSCODE_PART_SUFFIX}",
+            rand::random::<u32>()
+        ));
+        match tokio::fs::File::create(&tmp).await {
+            Ok(file) => Some(TranscodeWriter {
+                file,
+                tmp,
+                key: key.to_string(),
+                len: 0,
+                state: WriterState::Writing,
+            }),
+            Err(e) => {
+                log::warn!("transcode cache: {key}: {e}");
+                None
+            }
+        }
+    }
+
+    fn insert(&self, key: String, len: u64) -> Vec<PathBuf> {
+        let mut files = self.files.lock();
+        if let Some((old, _)) = files.entries.insert(key, (len, now_secs())) {
+            files.bytes -= old;
+        }
+        files.bytes += len;
+        let over = files.bytes > self.max_bytes;
+        drop(files);
+
+        if over {
+            self.evict(self.max_bytes * EVICT_TARGET_PERCENT / 100)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let mut files = self.files.lock();
+        if let Some((len, _)) = files.entries.remove(key) {
+            files.bytes -= len;
+        }
+    }
+
+    /// drops the least recently used entries until the cache fits in `target` bytes, returning
+    /// the files to delete.
+    fn evict(&self, target: u64) -> Vec<PathBuf> {
+        let mut files = self.files.lock();
+        let mut entries: Vec<(u64, String)> = files
+            .entries
+            .iter()
+            .map(|(key, (_, used))| (*used, key.clone()))
+            .collect();
+        entries.sort_unstable();
+
+        let mut evicted = Vec::new();
+        for (_, key) in entries {
+            if files.bytes <= target {
+                break;
+            }
+            if let Some((len, _)) = files.entries.remove(&key) {
+                files.bytes -= len;
+                evicted.push(self.dir.join(key));
+            }
+        }
+        drop(files);
+
+        if !evicted.is_empty() {
+            log::info!("transcode cache: evicted {} files", evicted.len());
+        }
+        evicted
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum WriterState {
+    Writing,
+    Failed,
+    Finished,
+}
+
+/// a transcode being written to the cache. writing is best-effort: failures are logged and the
+/// transcode is discarded, the stream it is copied from carries on regardless. dropping the
+/// writer before [`TranscodeWriter::finish`] discards it as well.
+pub struct TranscodeWriter {
+    file: tokio::fs::File,
+    tmp: PathBuf,
+    key: String,
+    len: u64,
+    state: WriterState,
+}
+
+impl TranscodeWriter {
+    pub async fn write(&mut self, bytes: &[u8]) {
+        if self.state != WriterState::Writing {
+            return;
+        }
+        match self.file.write_all(bytes).await {
+            Ok(()) => self.len += bytes.len() as u64,
+            Err(e) => {
+                log::warn!("transcode cache: {}: {e}", self.key);
+                self.state = WriterState::Failed;
+            }
+        }
+    }
+
+    pub const fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// overwrites the start of the file, e.g. to fill in sizes once the length is known.
+    pub async fn rewrite_start(&mut self, bytes: &[u8]) {
+        if self.state != WriterState::Writing {
+            return;
+        }
+        let result = async {
+            self.file.seek(SeekFrom::Start(0)).await?;
+            self.file.write_all(bytes).await?;
+            self.file.seek(SeekFrom::End(0)).await.map(|_| ())
+        }
+        .await;
+        if let Err(e) = result {
+            log::warn!("transcode cache: {}: {e}", self.key);
+            self.state = WriterState::Failed;
+        }
+    }
+
+    /// opens what has been written so far for reading, `None` if writing failed.
+    pub async fn reopen(&mut self) -> Option<tokio::fs::File> {
+        if self.state != WriterState::Writing {
+            return None;
+        }
+        let result = async {
+            self.file.flush().await?;
+            tokio::fs::File::open(&self.tmp).await
+        }
+        .await;
+        match result {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::warn!("transcode cache: {}: {e}", self.key);
+                self.state = WriterState::Failed;
+                None
+            }
+        }
+    }
+
+    /// makes the complete transcode available under its key.
+    pub async fn finish(mut self, cache: &TranscodeCache) {
+        if self.state != WriterState::Writing {
+            return;
+        }
+        // a single file larger than the whole budget would just flush everything else
+        if self.len > cache.max_bytes {
+            return;
+        }
+        let path = cache.dir.join(&self.key);
+        let result = async {
+            self.file.flush().await?;
+            tokio::fs::rename(&self.tmp, &path).await
+        }
+        .await;
+        if let Err(e) = result {
+            log::warn!("transcode cache: {}: {e}", self.key);
+            return;
+        }
+        self.state = WriterState::Finished;
+
+        let evicted = cache.insert(self.key.clone(), self.len);
+        log::debug!("transcode cache: stored {} ({} bytes)", self.key, self.len);
+        for path in evicted {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                log::warn!("transcode cache: {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+impl Drop for TranscodeWriter {
+    fn drop(&mut self) {
+        if self.state != WriterState::Finished {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
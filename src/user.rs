@@ -0,0 +1,218 @@
+use std::path::PathBuf;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+
+use crate::prelude::*;
+
+/// what a user may do. roles subsonic defines but this server has no use for are not stored.
+#[derive(Clone, Copy, Debug, Default, Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Roles {
+    /// manage users and edit anyone's playlists
+    pub admin: bool,
+    pub stream: bool,
+    pub download: bool,
+    /// create, edit and delete playlists
+    pub playlist: bool,
+    pub share: bool,
+}
+
+impl Roles {
+    pub const ALL: Self = Self {
+        admin: true,
+        stream: true,
+        download: true,
+        playlist: true,
+        share: true,
+    };
+}
+
+/// a subsonic account. passwords are kept in plain text because token authentication hashes
+/// them with a client-chosen salt. accounts without one can only use api keys.
+#[derive(Clone, Debug, Serialize, serde::Deserialize)]
+pub struct User {
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub email: Option<String>,
+    pub roles: Roles,
+}
+
+/// an api key as stored. only the key's sha-256 is kept, the key itself is shown once on issue.
+#[derive(Clone, Debug, Serialize, serde::Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub username: String,
+    pub name: Option<String>,
+    pub created: DateTime<Utc>,
+    hash: String,
+}
+
+impl ApiKey {
+    fn hash(key: &str) -> String {
+        format!("{:x}", Sha256::digest(key.as_bytes()))
+    }
+}
+
+#[derive(Clone, Default, Serialize, serde::Deserialize)]
+struct UserFile {
+    users: Vec<User>,
+    #[serde(default)]
+    api_keys: Vec<ApiKey>,
+    #[serde(default)]
+    next_key_id: u64,
+}
+
+/// json-file backed user storage, rewritten in full on every change. the account from the
+/// config file is a built-in admin on top of these and can't be changed through the api.
+pub struct UserStore {
+    path: PathBuf,
+    admin: User,
+    file: UserFile,
+}
+
+impl UserStore {
+    pub fn load(path: impl Into<PathBuf>, admin: &Account) -> Result<Self> {
+        let path = path.into();
+        let file = match std::fs::File::open(&path) {
+            Ok(rdr) => serde_json::from_reader(rdr)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let admin = User {
+            username: admin.user().to_string(),
+            password: admin.pass().map(str::to_string),
+            email: None,
+            roles: Roles::ALL,
+        };
+        let mut store = Self { path, admin, file };
+
+        // without a password the built-in admin would have no way in
+        let username = store.admin.username.clone();
+        if store.admin.password.is_none() && store.keys(&username).is_empty() {
+            let (_, key) = store.issue_key(&username, Some("initial".to_string()))?;
+            // the key itself is printed rather than logged, so it doesn't end up in log files
+            log::warn!("No password configured for {username}, issued an api key (see stdout).");
+            println!("Initial api key for {username}: {key}");
+        }
+        Ok(store)
+    }
+
+    /// applies `f` to a copy of the store and persists it, only keeping the change once it's
+    /// saved.
+    fn change<T>(&mut self, f: impl FnOnce(&mut UserFile) -> T) -> Result<T> {
+        let mut file = self.file.clone();
+        let result = f(&mut file);
+        // write to a sibling file first so a crash never leaves a truncated store behind
+        let tmp = self.path.with_extension("json.tmp");
+        serde_json::to_writer_pretty(std::fs::File::create(&tmp)?, &file)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = file;
+        Ok(result)
+    }
+
+    /// every user, the built-in admin first.
+    pub fn all(&self) -> Vec<User> {
+        std::iter::once(&self.admin)
+            .chain(&self.file.users)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, username: &str) -> Option<&User> {
+        std::iter::once(&self.admin)
+            .chain(&self.file.users)
+            .find(|u| u.username == username)
+    }
+
+    /// whether `username` is the account from the config file.
+    pub fn is_builtin(&self, username: &str) -> bool {
+        self.admin.username == username
+    }
+
+    /// adds a user. returns `None` if the name is taken.
+    pub fn create(&mut self, user: User) -> Option<Result<()>> {
+        if self.get(&user.username).is_some() {
+            return None;
+        }
+        Some(self.change(|file| file.users.push(user)))
+    }
+
+    /// applies `f` to the user and persists the result. returns `None` if they don't exist or
+    /// are the built-in admin.
+    pub fn update<F: FnOnce(&mut User)>(&mut self, username: &str, f: F) -> Option<Result<()>> {
+        let pos = self
+            .file
+            .users
+            .iter()
+            .position(|u| u.username == username)?;
+        Some(self.change(|file| f(&mut file.users[pos])))
+    }
+
+    /// deletes the user along with their api keys. returns `None` if they don't exist or are the
+    /// built-in admin.
+    pub fn delete(&mut self, username: &str) -> Option<Result<()>> {
+        let pos = self
+            .file
+            .users
+            .iter()
+            .position(|u| u.username == username)?;
+        Some(self.change(|file| {
+            file.users.remove(pos);
+            file.api_keys.retain(|k| k.username != username);
+        }))
+    }
+
+    /// the user an api key belongs to, if it's valid.
+    pub fn by_api_key(&self, key: &str) -> Option<&User> {
+        let hash = ApiKey::hash(key);
+        let key = self
+            .file
+            .api_keys
+            .iter()
+            .find(|k| constant_time_eq(k.hash.as_bytes(), hash.as_bytes()))?;
+        self.get(&key.username)
+    }
+
+    /// the api keys issued to `username`.
+    pub fn keys(&self, username: &str) -> Vec<ApiKey> {
+        self.file
+            .api_keys
+            .iter()
+            .filter(|k| k.username == username)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_key(&self, id: &str) -> Option<&ApiKey> {
+        self.file.api_keys.iter().find(|k| k.id == id)
+    }
+
+    /// issues a new api key to `username`, returning its stored form and the key itself.
+    pub fn issue_key(&mut self, username: &str, name: Option<String>) -> Result<(ApiKey, String)> {
+        let key = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        let api_key = self.change(|file| {
+            file.next_key_id += 1;
+            let api_key = ApiKey {
+                id: file.next_key_id.to_string(),
+                username: username.to_string(),
+                name,
+                created: Utc::now(),
+                hash: ApiKey::hash(&key),
+            };
+            file.api_keys.push(api_key.clone());
+            api_key
+        })?;
+        Ok((api_key, key))
+    }
+
+    /// returns `None` if no key has that id.
+    pub fn revoke_key(&mut self, id: &str) -> Option<Result<()>> {
+        let pos = self.file.api_keys.iter().position(|k| k.id == id)?;
+        Some(self.change(|file| {
+            file.api_keys.remove(pos);
+        }))
+    }
+}
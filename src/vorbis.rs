@@ -0,0 +1,185 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use librespot::audio::{AudioDecrypt, AudioFile};
+use librespot::metadata::audio::{AudioFileFormat, AudioItem};
+
+use crate::prelude::*;
+
+// spotify prefixes its ogg vorbis files with a proprietary page holding normalisation data
+const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+// where in that page the track and album gains and peaks sit, as four little-endian f32s
+const SPOTIFY_NORMALISATION_OFFSET: u64 = 144;
+
+// spotify's vorbis encodes, highest bitrate (kbps) first
+const VORBIS_FORMATS: [(u32, AudioFileFormat); 3] = [
+    (320, AudioFileFormat::OGG_VORBIS_320),
+    (160, AudioFileFormat::OGG_VORBIS_160),
+    (96, AudioFileFormat::OGG_VORBIS_96),
+];
+
+// bytes read from the decrypted file per chunk handed to the client
+const VORBIS_READ_SIZE: usize = 16 * 1024;
+
+// an ogg page is at most 27 + 255 + 255 * 255 bytes, so a window of twice that always holds the
+// start of one unless the file ends first
+const OGG_MAX_PAGE_SIZE: usize = 65307;
+const OGG_PAGE_HEADER_SIZE: usize = 27;
+
+/// the highest spotify vorbis bitrate that doesn't exceed `bit_rate` (kbps).
+pub fn vorbis_bit_rate(bit_rate: u32) -> u32 {
+    VORBIS_FORMATS
+        .iter()
+        .map(|(kbps, _)| *kbps)
+        .find(|kbps| *kbps <= bit_rate)
+        .unwrap_or(VORBIS_FORMATS[VORBIS_FORMATS.len() - 1].0)
+}
+
+/// a decrypted spotify ogg vorbis file, forwarded to clients as-is without decoding.
+pub struct VorbisFile {
+    reader: AudioDecrypt<AudioFile>,
+    len: u64,
+    bit_rate: u32,
+}
+
+impl VorbisFile {
+    /// opens the vorbis encode of `uri` closest to `bit_rate` (kbps), preferring lower bitrates
+    /// and falling back to higher ones if that's all spotify has.
+    pub async fn open(session: &Session, uri: SpotifyUri, bit_rate: u32) -> Result<Self> {
+        let track_id = SpotifyId::try_from(&uri)?;
+        let item = AudioItem::get_file(session, uri).await?;
+        if let Err(reason) = item.availability {
+            return Err(anyhow!("Track is unavailable: {reason:?}"));
+        }
+
+        let (bit_rate, file_id) = VORBIS_FORMATS
+            .iter()
+            .filter(|(kbps, _)| *kbps <= bit_rate)
+            .chain(VORBIS_FORMATS.iter().rev())
+            .find_map(|(kbps, format)| item.files.get(format).map(|id| (*kbps, *id)))
+            .ok_or_else(|| anyhow!("Track has no Ogg Vorbis file"))?;
+
+        let file = AudioFile::open(session, file_id, (bit_rate * 1024 / 8) as usize).await?;
+        let len = file.get_stream_loader_controller()?.len() as u64;
+
+        // like librespot, carry on without a key: not every file is encrypted
+        let key = match session.audio_key().request(track_id, file_id).await {
+            Ok(key) => Some(key),
+            Err(e) => {
+                log::warn!("vorbis: no audio key for {file_id}, continuing without: {e}");
+                None
+            }
+        };
+
+        Ok(Self {
+            reader: AudioDecrypt::new(key, file),
+            len,
+            bit_rate,
+        })
+    }
+
+    pub const fn bit_rate(&self) -> u32 {
+        self.bit_rate
+    }
+
+    /// length of the ogg stream, i.e. the file without spotify's header.
+    pub const fn stream_len(&self) -> u64 {
+        self.len.saturating_sub(SPOTIFY_OGG_HEADER_END)
+    }
+
+    /// reads spotify's loudness normalisation data. blocks like [`VorbisFile::pump`].
+    pub fn replay_gain(mut self) -> Result<ReplayGain> {
+        self.reader
+            .seek(SeekFrom::Start(SPOTIFY_NORMALISATION_OFFSET))?;
+        let mut buf = [0u8; 16];
+        self.reader.read_exact(&mut buf)?;
+
+        let field = |i: usize| f32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        Ok(ReplayGain {
+            track_gain: field(0),
+            track_peak: field(4),
+            album_gain: field(8),
+            album_peak: field(12),
+        })
+    }
+
+    /// reads the ogg stream from byte `start`, or from `offset_ms` onwards, and sends it to `tx`
+    /// until the file ends or the receiver is dropped. the file fetches from spotify's cdn with
+    /// blocking reads, so this must run on a blocking thread.
+    pub fn pump(mut self, offset_ms: u32, start: u64, tx: Sender<Vec<u8>>) -> Result<()> {
+        self.reader
+            .seek(SeekFrom::Start(SPOTIFY_OGG_HEADER_END + start))?;
+
+        if offset_ms > 0 {
+            // decoders need the vorbis headers before jumping into the middle of the stream
+            let (headers, serial) = self.read_header_pages()?;
+            if tx.blocking_send(headers).is_err() {
+                return Ok(());
+            }
+            let target = SPOTIFY_OGG_HEADER_END + offset_ms as u64 * self.bit_rate as u64 / 8;
+            self.seek_to_page(target.min(self.len), serial)?;
+        }
+
+        loop {
+            let mut buf = vec![0u8; VORBIS_READ_SIZE];
+            let n = self.reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            buf.truncate(n);
+            if tx.blocking_send(buf).is_err() {
+                // the client went away
+                return Ok(());
+            }
+        }
+    }
+
+    /// reads the leading pages that carry no audio (granule position 0), returning them along
+    /// with the stream's serial number. leaves the reader past the first audio page.
+    fn read_header_pages(&mut self) -> Result<(Vec<u8>, u32)> {
+        let mut headers = Vec::new();
+        loop {
+            let mut header = [0u8; OGG_PAGE_HEADER_SIZE];
+            self.reader.read_exact(&mut header)?;
+            if &header[..4] != b"OggS" {
+                return Err(anyhow!("Malformed Ogg page"));
+            }
+            let granule = u64::from_le_bytes(header[6..14].try_into()?);
+            let serial = u32::from_le_bytes(header[14..18].try_into()?);
+
+            let mut segments = vec![0u8; header[26] as usize];
+            self.reader.read_exact(&mut segments)?;
+            let mut body = vec![0u8; segments.iter().map(|&s| s as usize).sum()];
+            self.reader.read_exact(&mut body)?;
+
+            if granule != 0 {
+                return Ok((headers, serial));
+            }
+            headers.extend_from_slice(&header);
+            headers.extend_from_slice(&segments);
+            headers.extend_from_slice(&body);
+        }
+    }
+
+    /// positions the reader at the first page of `serial` starting at or after `pos`, or at the
+    /// end of the file if there is none.
+    fn seek_to_page(&mut self, pos: u64, serial: u32) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(pos))?;
+
+        let mut window = Vec::with_capacity(OGG_MAX_PAGE_SIZE * 2);
+        (&mut self.reader)
+            .take(OGG_MAX_PAGE_SIZE as u64 * 2)
+            .read_to_end(&mut window)?;
+
+        // the capture pattern can occur inside audio data, so also check version and serial
+        let page = window
+            .windows(OGG_PAGE_HEADER_SIZE)
+            .position(|h| &h[..4] == b"OggS" && h[4] == 0 && h[14..18] == serial.to_le_bytes());
+
+        let target = match page {
+            Some(offset) => pos + offset as u64,
+            None => self.len,
+        };
+        self.reader.seek(SeekFrom::Start(target))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use crate::prelude::*;
+
+// riff and data chunk sizes for a stream of unknown length
+const WAV_UNKNOWN_SIZE: u32 = u32::MAX;
+
+const WAV_HEADER_SIZE: usize = 44;
+
+/// passes the decoded pcm through as 16-bit stereo wav.
+pub struct WavStreamer;
+
+impl AudioEncoder for WavStreamer {
+    fn sample_rate(&self) -> u32 {
+        SOURCE_SAMPLE_RATE
+    }
+
+    /// canonical 44 byte riff/wave header, with the sizes left at their maximum since the length
+    /// isn't known up front.
+    fn header_bytes(&mut self, _tags: &TrackTags) -> Vec<u8> {
+        let channels: u16 = 2;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+
+        let mut header = Vec::with_capacity(WAV_HEADER_SIZE);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&WAV_UNKNOWN_SIZE.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+        header.extend_from_slice(&1u16.to_le_bytes()); // integer pcm
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&SOURCE_SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(SOURCE_SAMPLE_RATE * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits_per_sample.to_le_bytes());
+
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&WAV_UNKNOWN_SIZE.to_le_bytes());
+        header
+    }
+
+    fn encode_chunk(&mut self, pcm: &[i16]) -> Vec<u8> {
+        pcm.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    fn flush(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// replaces the placeholder riff and data chunk sizes with the real ones.
+    fn finish_header(&self, header: &mut [u8], len: u64) {
+        if header.len() < WAV_HEADER_SIZE || len < WAV_HEADER_SIZE as u64 {
+            return;
+        }
+        let len = u32::try_from(len).unwrap_or(WAV_UNKNOWN_SIZE);
+        header[4..8].copy_from_slice(&(len - 8).to_le_bytes());
+        header[40..44].copy_from_slice(&(len - WAV_HEADER_SIZE as u32).to_le_bytes());
+    }
+}